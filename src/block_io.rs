@@ -0,0 +1,457 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+
+/// Settings of the reads and writes of merges: the runs and the input read by a merge and the file it writes.
+/// Runs formed in RAM and inputs read from a memory map always go through the page cache.
+#[derive(Debug, Clone, Copy)]
+pub struct IoOptions {
+    /// Size of the blocks read ahead from the merged files and written behind to the merge output
+    pub block_size: usize,
+    /// Bypass the page cache with `O_DIRECT` while merging, falling back to buffered I/O where the file system doesn't support it
+    pub direct_io: bool,
+    /// Advise the kernel that merged files are read sequentially and drop the pages already read from the page cache
+    pub fadvise: bool,
+    /// Reserve the disk space of merge outputs before writing them
+    pub preallocate: bool,
+}
+
+impl Default for IoOptions {
+    fn default() -> Self {
+        IoOptions { block_size: 1 << 20, direct_io: false, fadvise: false, preallocate: false }
+    }
+}
+
+impl IoOptions {
+    /// Blocks held by a `PrefetchReader` or a `WriteBehind`: the one used by the caller,
+    /// the one waiting to be handed over and the one used by the thread, plus the aligned staging block of direct writes
+    fn stream_blocks(&self) -> usize {
+        if self.direct_io { 4 } else { 3 }
+    }
+
+    /// Heap bytes of the blocks held by a `PrefetchReader` or a `WriteBehind`
+    pub fn stream_buffers_size(&self) -> usize {
+        let block_size = usize::max(1, self.block_size);
+        let block_size = if self.direct_io { aligned_size(block_size) + DIRECT_IO_ALIGNMENT } else { block_size };
+        self.stream_blocks() * block_size
+    }
+
+    /// Shrinks the blocks so that the buffers of `streams` streams take at most `limit` bytes,
+    /// as far as the alignment of direct I/O allows
+    pub fn limit_buffers(mut self, streams: usize, limit: usize) -> IoOptions {
+        self.block_size = usize::max(1, usize::min(self.block_size, limit / (streams * self.stream_blocks())));
+        self
+    }
+}
+
+/// Alignment of the buffers, offsets and lengths of `O_DIRECT` reads and writes
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    pub fn set_direct(file: &File, direct: bool) -> io::Result<()> {
+        let fd = file.as_raw_fd();
+        // SAFETY: `fd` stays open while `file` is borrowed
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if direct { flags | libc::O_DIRECT } else { flags & !libc::O_DIRECT };
+        // SAFETY: as above
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn advise_sequential(file: &File) {
+        // SAFETY: as above, the advice doesn't change the contents of the file
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+    }
+
+    pub fn advise_dont_need(file: &File, offset: u64, length: usize) {
+        // SAFETY: as above
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, length as libc::off_t, libc::POSIX_FADV_DONTNEED) };
+    }
+
+    pub fn preallocate(file: &File, length: u64) {
+        // SAFETY: as above, `FALLOC_FL_KEEP_SIZE` only reserves blocks without changing the file size
+        unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, length as libc::off_t) };
+    }
+
+    pub fn punch_hole(file: &File, offset: u64, length: u64) -> io::Result<()> {
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        // SAFETY: as above, the punched bytes are read back as zeros and the file size is kept
+        if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as libc::off_t, length as libc::off_t) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The tuning is only available on Linux, elsewhere files are always read and written through the page cache
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+
+    pub fn set_direct(_: &File, _: bool) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    pub fn advise_sequential(_: &File) {}
+
+    pub fn advise_dont_need(_: &File, _: u64, _: usize) {}
+
+    pub fn preallocate(_: &File, _: u64) {}
+
+    pub fn punch_hole(_: &File, _: u64, _: u64) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+/// Frees the disk blocks of a byte range of a file opened for writing, which then reads as zeros.
+/// Only the blocks wholly inside the range are freed, it fails where the file system can't punch holes.
+pub fn punch_hole(file: &File, range: Range<u64>) -> io::Result<()> {
+    sys::punch_hole(file, range.start, range.end.saturating_sub(range.start))
+}
+
+fn is_direct_io_unsupported(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::InvalidInput
+}
+
+fn aligned_size(size: usize) -> usize {
+    size.div_ceil(DIRECT_IO_ALIGNMENT) * DIRECT_IO_ALIGNMENT
+}
+
+/// Reads from `buffer[*length..]` until the buffer is full or the file ends.
+/// `length` counts the bytes read even when it fails, so that a retry goes on after them.
+fn read_full(file: &mut File, buffer: &mut [u8], length: &mut usize) -> io::Result<()> {
+    while *length < buffer.len() {
+        match file.read(&mut buffer[*length..]) {
+            Ok(0) => break,
+            Ok(read) => *length += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Block read by the reading thread, with the position where its data starts
+type ReadBlock = io::Result<(Vec<u8>, usize)>;
+
+/// Called by the reading thread with the file and the byte range of every block once it is read
+pub type Release = Box<dyn FnMut(&File, Range<u64>) + Send>;
+
+/// Reader of a file whose next block is read by its own thread while the current one is consumed,
+/// so reading only waits for the disk when it is slower than the consumer
+pub struct PrefetchReader {
+    blocks: Option<mpsc::Receiver<ReadBlock>>,
+    spare_blocks: mpsc::Sender<Vec<u8>>,
+    block: Vec<u8>,
+    position: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PrefetchReader {
+    /// Reads the byte range of the file
+    pub fn new(file: File, range: Range<u64>, options: &IoOptions) -> PrefetchReader {
+        PrefetchReader::start(file, range, options, None)
+    }
+
+    /// Reads the byte range of the file, which is read only once, handing every block read over to `release`
+    pub fn consuming(file: File, range: Range<u64>, options: &IoOptions, release: Release) -> PrefetchReader {
+        PrefetchReader::start(file, range, options, Some(release))
+    }
+
+    fn start(mut file: File, range: Range<u64>, options: &IoOptions, mut release: Option<Release>) -> PrefetchReader {
+        let options = *options;
+        let (block_sender, blocks) = mpsc::sync_channel(1);
+        let (spare_blocks, spare_receiver) = mpsc::channel::<Vec<u8>>();
+
+        let thread = thread::spawn(move || {
+            let mut direct = options.direct_io && sys::set_direct(&file, true).is_ok();
+            // blocks of small ranges don't need to be larger than the range
+            let range_size = usize::try_from(range.end.saturating_sub(range.start)).unwrap_or(usize::MAX);
+            let block_size = usize::max(1, usize::min(range_size, options.block_size));
+            let block_size = if direct { aligned_size(block_size) } else { block_size };
+            if options.fadvise {
+                sys::advise_sequential(&file);
+            }
+
+            // direct reads start at an aligned offset, the bytes before the range are skipped
+            let mut offset = if direct { range.start / DIRECT_IO_ALIGNMENT as u64 * DIRECT_IO_ALIGNMENT as u64 } else { range.start };
+            if let Err(err) = file.seek(SeekFrom::Start(offset)) {
+                let _ = block_sender.send(Err(err));
+                return;
+            }
+
+            while offset < range.end {
+                // with direct I/O the data starts at the first aligned byte of the block
+                let mut block = spare_receiver.try_recv().unwrap_or_else(|_| Vec::with_capacity(block_size + DIRECT_IO_ALIGNMENT));
+                let start = if direct { block.as_ptr().align_offset(DIRECT_IO_ALIGNMENT) } else { 0 };
+                block.resize(start + block_size, 0);
+
+                let mut length = 0;
+                let mut result = read_full(&mut file, &mut block[start..], &mut length);
+                // the direct reads done before the failure are aligned, the rest of the block is read after them
+                if direct && matches!(&result, Err(err) if is_direct_io_unsupported(err)) && sys::set_direct(&file, false).is_ok() {
+                    direct = false;
+                    result = read_full(&mut file, &mut block[start..], &mut length);
+                }
+
+                if let Err(err) = result {
+                    let _ = block_sender.send(Err(err));
+                    break;
+                }
+                if options.fadvise {
+                    sys::advise_dont_need(&file, offset, length);
+                }
+                let skipped = usize::min(range.start.saturating_sub(offset) as usize, length);
+                let used = u64::min(range.end.saturating_sub(offset), length as u64) as usize;
+                if let Some(release) = release.as_mut().filter(|_| used > skipped) {
+                    release(&file, offset + skipped as u64..offset + used as u64);
+                }
+                block.truncate(start + used);
+                offset += length as u64;
+
+                if length == 0 || block_sender.send(Ok((block, start + skipped))).is_err() {
+                    break;
+                }
+            }
+        });
+
+        PrefetchReader { blocks: Some(blocks), spare_blocks, block: Vec::new(), position: 0, thread: Some(thread) }
+    }
+}
+
+impl Read for PrefetchReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let length = usize::min(available.len(), buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+impl BufRead for PrefetchReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.block.len() {
+            let _ = self.spare_blocks.send(mem::take(&mut self.block));
+            self.position = 0;
+            // the reading thread hangs up at the end of the range
+            if let Some(Ok(block)) = self.blocks.as_ref().map(mpsc::Receiver::recv) {
+                (self.block, self.position) = block?;
+            }
+        }
+        Ok(&self.block[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = usize::min(self.position + amount, self.block.len());
+    }
+}
+
+impl Drop for PrefetchReader {
+    fn drop(&mut self) {
+        // the reading thread stops once it can't send its block anymore
+        drop(self.blocks.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Writer to a file that hands full blocks over to its own thread, so writing doesn't wait for the disk
+/// unless the thread is still busy with the previous block.
+/// `flush` only hands the buffered data over, `finish` waits until everything is written.
+pub struct WriteBehind {
+    blocks: Option<mpsc::SyncSender<Vec<u8>>>,
+    spare_blocks: mpsc::Receiver<Vec<u8>>,
+    block: Vec<u8>,
+    block_size: usize,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl WriteBehind {
+    /// `size` is the expected size of the file, which is reserved on the disk before writing when given
+    pub fn new(mut file: File, options: &IoOptions, size: Option<u64>) -> WriteBehind {
+        let options = *options;
+        let block_size = usize::max(1, options.block_size);
+        let (blocks, block_receiver) = mpsc::sync_channel::<Vec<u8>>(1);
+        let (spare_sender, spare_blocks) = mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            if let (true, Some(size)) = (options.preallocate, size) {
+                sys::preallocate(&file, size);
+            }
+
+            let mut direct = options.direct_io && sys::set_direct(&file, true).is_ok();
+            let mut staging = Vec::new();
+            let mut staged = 0;
+            if direct {
+                staging = vec![0_u8; aligned_size(block_size) + DIRECT_IO_ALIGNMENT];
+            }
+            let staging_start = staging.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+
+            for mut block in block_receiver {
+                if direct {
+                    // direct writes take whole aligned blocks, so the data is gathered in an aligned buffer
+                    let region = &mut staging[staging_start..staging_start + aligned_size(block_size)];
+                    let mut data = block.as_slice();
+                    while !data.is_empty() {
+                        let length = usize::min(region.len() - staged, data.len());
+                        region[staged..staged + length].copy_from_slice(&data[..length]);
+                        staged += length;
+                        data = &data[length..];
+                        if staged < region.len() {
+                            continue;
+                        }
+                        match file.write_all(region) {
+                            Ok(()) => staged = 0,
+                            Err(err) if is_direct_io_unsupported(&err) && sys::set_direct(&file, false).is_ok() => {
+                                direct = false;
+                                file.write_all(&region[..staged])?;
+                                file.write_all(data)?;
+                                staged = 0;
+                                break;
+                            }
+                            Err(err) => return Err(err),
+                        }
+                    }
+                } else {
+                    file.write_all(&block)?;
+                }
+                block.clear();
+                let _ = spare_sender.send(block);
+            }
+
+            // the tail that doesn't fill an aligned block is written through the page cache
+            if staged > 0 {
+                sys::set_direct(&file, false)?;
+                file.write_all(&staging[staging_start..staging_start + staged])?;
+            }
+            file.flush()
+        });
+
+        WriteBehind { blocks: Some(blocks), spare_blocks, block: Vec::with_capacity(block_size), block_size, thread: Some(thread) }
+    }
+
+    /// Writes the rest of the data and waits for the writing thread, returning the first error it met
+    pub fn finish(mut self) -> io::Result<()> {
+        self.send_block()?;
+        self.join()
+    }
+
+    fn send_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let next = self.spare_blocks.try_recv().unwrap_or_else(|_| Vec::with_capacity(self.block_size));
+        let block = mem::replace(&mut self.block, next);
+        match self.blocks.as_ref().map(|blocks| blocks.send(block)) {
+            Some(Ok(())) => Ok(()),
+            // the writing thread only hangs up after an error
+            _ => match self.join() {
+                Ok(()) => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+                Err(err) => Err(err),
+            },
+        }
+    }
+
+    fn join(&mut self) -> io::Result<()> {
+        drop(self.blocks.take());
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("writing thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for WriteBehind {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.block.len() + buf.len() > self.block_size {
+            self.send_block()?;
+        }
+        self.block.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_block()
+    }
+}
+
+impl Drop for WriteBehind {
+    fn drop(&mut self) {
+        let _ = self.send_block();
+        let _ = self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_write_behind_and_prefetch() {
+        let path = std::env::temp_dir().join(format!("block_io_{}", std::process::id()));
+        let lines: Vec<String> = (0..10_000).map(|i| format!("line {}", i)).collect();
+
+        let options = IoOptions { block_size: 100, ..IoOptions::default() };
+        let mut output = WriteBehind::new(File::create(&path).unwrap(), &options, None);
+        for line in &lines {
+            writeln!(output, "{}", line).unwrap();
+        }
+        output.finish().unwrap();
+
+        let size = fs::metadata(&path).unwrap().len();
+        let input = PrefetchReader::new(File::open(&path).unwrap(), 0..size, &IoOptions { block_size: 7, ..options });
+        let read: Vec<String> = input.lines().map(Result::unwrap).collect();
+        assert_eq!(read, lines);
+
+        let mut input = PrefetchReader::new(File::open(&path).unwrap(), 5..size - 3, &IoOptions::default());
+        let mut data = Vec::new();
+        input.read_to_end(&mut data).unwrap();
+        assert_eq!(data, fs::read(&path).unwrap()[5..size as usize - 3]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_direct_io_with_unaligned_size() {
+        let path = std::env::temp_dir().join(format!("block_io_direct_{}", std::process::id()));
+        let data: Vec<u8> = (0..3 * DIRECT_IO_ALIGNMENT + 123).map(|i| (i % 251) as u8).collect();
+        let options = IoOptions { block_size: 5000, direct_io: true, fadvise: true, preallocate: true };
+
+        let mut output = WriteBehind::new(File::create(&path).unwrap(), &options, Some(data.len() as u64));
+        for chunk in data.chunks(777) {
+            output.write_all(chunk).unwrap();
+        }
+        output.finish().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), data.len() as u64);
+
+        let mut input = PrefetchReader::new(File::open(&path).unwrap(), 0..data.len() as u64, &options);
+        let mut read = Vec::new();
+        input.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        let mut input = PrefetchReader::new(File::open(&path).unwrap(), 5000..9000, &options);
+        let mut read = Vec::new();
+        input.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data[5000..9000]);
+        let _ = fs::remove_file(path);
+    }
+}
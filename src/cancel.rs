@@ -0,0 +1,98 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+use crate::error::SortError;
+
+/// Signal received by the handler of `CancelToken::on_signals`, 0 until one is received
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Stops a sort once it is cancelled, at the next check of its steps: the merges check it every few thousand values,
+/// the sorts in RAM every 64 Ki values, the parallel parsing every MiB and the parallel formatting every chunk.
+/// The jobs waiting for a worker aren't started. Parsing and writing on a single thread only check it between
+/// the parts of the input. The sort then removes its temporary files, leaves the output untouched and fails
+/// with `SortError::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    /// Whether the token is cancelled by SIGINT and SIGTERM too
+    signals: bool,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Token cancelled when the process receives SIGINT or SIGTERM. A second signal stops the process at once.
+    #[cfg(unix)]
+    pub fn on_signals() -> io::Result<CancelToken> {
+        extern "C" fn handle_signal(signal: libc::c_int) {
+            SIGNAL.store(signal, Ordering::SeqCst);
+            // SAFETY: `signal` is async-signal-safe
+            unsafe { libc::signal(signal, libc::SIG_DFL) };
+        }
+
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            // SAFETY: the handler only stores to an atomic and calls async-signal-safe functions
+            if unsafe { libc::signal(signal, handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(CancelToken { cancelled: Arc::default(), signals: true })
+    }
+
+    /// Signals are only handled on Unix, elsewhere they stop the process as usual
+    #[cfg(not(unix))]
+    pub fn on_signals() -> io::Result<CancelToken> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// Cancels the sorts given this token or one of its clones, from any thread
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || (self.signals && received_signal().is_some())
+    }
+
+    /// Fails once the token is cancelled, to stop a step of the sort with `?`
+    pub(crate) fn check(&self) -> Result<(), SortError> {
+        if self.is_cancelled() {
+            return Err(SortError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+/// Signal that cancelled the tokens of `CancelToken::on_signals`
+pub fn received_signal() -> Option<i32> {
+    Some(SIGNAL.load(Ordering::SeqCst)).filter(|&signal| signal != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_token() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(clone.check().is_ok());
+        token.cancel();
+        assert!(matches!(clone.check(), Err(SortError::Cancelled)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cancel_on_signals() {
+        let token = CancelToken::on_signals().unwrap();
+        assert!(!token.is_cancelled());
+        // SAFETY: the handler has been installed, it doesn't stop the tests
+        unsafe { libc::raise(libc::SIGTERM) };
+        assert!(token.is_cancelled());
+        assert_eq!(received_signal(), Some(libc::SIGTERM));
+        // tokens made without the signals aren't cancelled by them
+        assert!(!CancelToken::new().is_cancelled());
+    }
+}
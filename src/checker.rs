@@ -1,34 +1,51 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::str::FromStr;
-
-pub fn is_sorted<T: FromStr + PartialOrd>(path: &str) -> Option<bool> {
-    let mut file = BufReader::with_capacity(10_000_000, File::open(path).unwrap());
-
-    let mut first_line = String::new();
-    let _ = file.read_line(&mut first_line);
-
-    let mut prev = match first_line.trim().parse::<T>() {
-        Ok(line) => line,
-        Err(_) => {
-            return None;
-        }
-    };
-    for (i, line) in file.lines().enumerate() {
-        match line.unwrap().trim().parse::<T>() {
-            Ok(current) => {
-                if i != 0 {
-                    if prev > current{
-                        return Some(false);
-                    }
-                } else {
-                    prev = current;
-                }
-            }
-            Err(_) => {
-                return None;
-            }
-        }
-    };
-    return Some(true);
-}
\ No newline at end of file
+use std::io::{self, BufRead};
+use crate::element::{line_token, Element, ParseOptions};
+use crate::error::SortError;
+use crate::file_reader::{map_file, STDIO_PATH};
+
+/// Whether the values of the file are sorted, `-` reading the standard input.
+/// Fails when the file can't be read or holds a value that can't be parsed.
+pub fn is_sorted<T: Element + Ord>(path: &str, options: &ParseOptions) -> Result<bool, SortError> {
+    if path == STDIO_PATH {
+        return is_sorted_from::<T, _>(io::stdin().lock(), path, options);
+    }
+    let data = map_file(path).map_err(SortError::io(path))?;
+    is_sorted_from::<T, _>(&data[..], path, options)
+}
+
+/// Same as `is_sorted`, for the values read from a stream named `path` in the errors
+pub fn is_sorted_from<T: Element + Ord, R: BufRead>(mut input: R, path: &str, options: &ParseOptions) -> Result<bool, SortError> {
+    let mut line = Vec::new();
+    let mut offset = 0;
+    let mut prev: Option<T> = None;
+    loop {
+        line.clear();
+        let length = input.read_until(b'\n', &mut line).map_err(SortError::io(path))?;
+        if length == 0 {
+            return Ok(true);
+        }
+        let token = line_token::<T>(&line);
+        let current = T::parse_bytes(token, options)
+            .map_err(|err| err.at::<T>(token.as_ptr() as usize - line.as_ptr() as usize, token).shifted(offset))
+            .map_err(SortError::parse(path))?;
+        if prev.as_ref().is_some_and(|prev| *prev > current) {
+            return Ok(false);
+        }
+        prev = Some(current);
+        offset += length as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sorted_from() {
+        let options = ParseOptions::default();
+        assert!(is_sorted_from::<u32, _>(&b"1\n2\n2\n"[..], STDIO_PATH, &options).unwrap());
+        assert!(!is_sorted_from::<u32, _>(&b"2\n1\n"[..], STDIO_PATH, &options).unwrap());
+        let err = is_sorted_from::<u32, _>(&b"1\n2\nx\n"[..], STDIO_PATH, &options).unwrap_err();
+        assert!(err.to_string().contains("`x` at byte 4"), "{}", err);
+    }
+}
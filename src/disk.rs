@@ -0,0 +1,247 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::process;
+use std::sync::Mutex;
+use crate::block_io;
+use crate::error::SortError;
+
+/// Bytes of temporary files a sort may have at once, shared by all its threads.
+/// They are taken before a file is written, so the sort stops before going over the limit.
+#[derive(Debug)]
+pub struct TempQuota {
+    limit: u64,
+    used: Mutex<u64>,
+}
+
+impl TempQuota {
+    pub fn new(limit: u64) -> TempQuota {
+        TempQuota { limit, used: Mutex::new(0) }
+    }
+
+    /// Accounts `bytes` about to be written, failing when they don't fit in the quota
+    pub fn take(&self, bytes: u64) -> Result<(), SortError> {
+        let mut used = self.used.lock().unwrap();
+        let needed = used.saturating_add(bytes);
+        if needed > self.limit {
+            return Err(SortError::TempQuota { needed, limit: self.limit });
+        }
+        *used = needed;
+        Ok(())
+    }
+
+    /// Fails when `bytes` more wouldn't fit in the quota, without taking them
+    pub fn check(&self, bytes: u64) -> Result<(), SortError> {
+        let needed = self.used().saturating_add(bytes);
+        if needed > self.limit {
+            return Err(SortError::TempQuota { needed, limit: self.limit });
+        }
+        Ok(())
+    }
+
+    /// Releases bytes of files that have been removed
+    pub fn give_back(&self, bytes: u64) {
+        let mut used = self.used.lock().unwrap();
+        *used = used.saturating_sub(bytes);
+    }
+
+    pub fn used(&self) -> u64 {
+        *self.used.lock().unwrap()
+    }
+
+    /// Charge of a file whose size is only known as it is written
+    pub fn charge(&self) -> Charge<'_> {
+        Charge { quota: self, bytes: 0 }
+    }
+}
+
+/// Bytes taken from a quota while a file is written, given back when it is dropped
+#[derive(Debug)]
+pub struct Charge<'a> {
+    quota: &'a TempQuota,
+    bytes: u64,
+}
+
+impl Charge<'_> {
+    /// Accounts `bytes` more written to the file, failing when they don't fit in the quota
+    pub fn take(&mut self, bytes: u64) -> Result<(), SortError> {
+        self.quota.take(bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Charge<'_> {
+    fn drop(&mut self) {
+        self.quota.give_back(self.bytes);
+    }
+}
+
+/// Byte ranges of a file that won't be read anymore. Only the blocks of the file system wholly inside them
+/// can be freed, so the blocks on their edges are freed once the ranges next to them are released too.
+#[derive(Debug)]
+pub struct Holes {
+    /// Released ranges, sorted and neither overlapping nor touching
+    ranges: Vec<Range<u64>>,
+    block_size: u64,
+    file_size: u64,
+}
+
+impl Holes {
+    pub fn new(block_size: u64, file_size: u64) -> Holes {
+        Holes { ranges: Vec::new(), block_size: u64::max(1, block_size), file_size }
+    }
+
+    /// Adds a released range, returning the range to punch a hole in and the bytes of the file it frees
+    /// that weren't freed before
+    pub fn release(&mut self, range: Range<u64>) -> (Range<u64>, u64) {
+        let (block_size, file_size) = (self.block_size, self.file_size);
+        let mut merged = range.clone();
+        let mut freed_before = 0;
+        self.ranges.retain(|released| {
+            if released.start > range.end || released.end < range.start {
+                return true;
+            }
+            merged = u64::min(merged.start, released.start)..u64::max(merged.end, released.end);
+            let blocks = whole_blocks(released, block_size, file_size);
+            freed_before += blocks.end - blocks.start;
+            false
+        });
+        let position = self.ranges.partition_point(|released| released.start < merged.start);
+        self.ranges.insert(position, merged.clone());
+
+        let blocks = whole_blocks(&merged, block_size, file_size);
+        let freed = blocks.end - blocks.start - freed_before;
+        // the last block is freed whole, the bytes after the end of the file being unused
+        let end = if blocks.end == file_size { blocks.end.div_ceil(block_size) * block_size } else { blocks.end };
+        (blocks.start..end, freed)
+    }
+}
+
+/// Bytes of a file in the blocks wholly inside the range, the last block counting up to the end of the file
+fn whole_blocks(range: &Range<u64>, block_size: u64, file_size: u64) -> Range<u64> {
+    let start = range.start.div_ceil(block_size) * block_size;
+    let end = if range.end >= file_size { file_size } else { range.end / block_size * block_size };
+    start..u64::max(start, end)
+}
+
+/// Bytes that unprivileged processes can still write to the file system holding `path`
+#[cfg(unix)]
+pub fn free_space(path: &str) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+
+    let path = CString::new(path).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stats` is only read once `statvfs` has filled it
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: as above
+    let stats = unsafe { stats.assume_init() };
+    // the fields are narrower than u64 on some platforms
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stats.f_bavail).saturating_mul(u64::from(stats.f_frsize)))
+}
+
+/// Whether both paths are on the same file system, so files are renamed from one to the other instead of copied
+#[cfg(unix)]
+pub fn same_file_system(first: &str, second: &str) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(std::fs::metadata(first)?.dev() == std::fs::metadata(second)?.dev())
+}
+
+/// Size of the blocks the file system allocates to the file
+#[cfg(unix)]
+pub fn block_size(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.blksize()
+}
+
+/// The free space is only known on Unix, elsewhere the sort isn't checked beforehand
+#[cfg(not(unix))]
+pub fn free_space(_: &str) -> io::Result<u64> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(not(unix))]
+pub fn same_file_system(_: &str, _: &str) -> io::Result<bool> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(not(unix))]
+pub fn block_size(_: &fs::Metadata) -> u64 {
+    4096
+}
+
+/// Whether the file system of the directory frees the blocks of punched holes, tried on a scratch file
+pub fn can_punch_holes(dir: &str) -> bool {
+    let path = Path::new(dir).join(format!(".punch_probe_{}", process::id()));
+    let punched = File::options().write(true).create(true).truncate(true).open(&path)
+        .and_then(|mut file| {
+            file.write_all(&[1; 8192])?;
+            block_io::punch_hole(&file, 0..4096)
+        });
+    let _ = fs::remove_file(&path);
+    punched.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_quota() {
+        let quota = TempQuota::new(100);
+        quota.take(60).unwrap();
+        assert!(matches!(quota.take(50), Err(SortError::TempQuota { needed: 110, limit: 100 })));
+        quota.give_back(20);
+        quota.take(50).unwrap();
+        assert_eq!(quota.used(), 90);
+
+        let mut charge = quota.charge();
+        charge.take(10).unwrap();
+        assert!(charge.take(1).is_err());
+        drop(charge);
+        assert_eq!(quota.used(), 90);
+    }
+
+    #[test]
+    fn test_holes() {
+        let mut holes = Holes::new(4096, 10_000);
+        assert_eq!(holes.release(0..3000), (0..0, 0));
+        assert_eq!(holes.release(3000..5000), (0..4096, 4096));
+        // the last block is punched whole, though the file ends before it
+        assert_eq!(holes.release(8000..10_000), (8192..12_288, 1808));
+        assert_eq!(holes.release(5000..8000), (0..12_288, 4096));
+        assert_eq!(holes.ranges, vec![0..10_000]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_free_space() {
+        let dir = std::env::temp_dir().to_string_lossy().into_owned();
+        assert!(free_space(&dir).unwrap() > 0);
+        assert!(same_file_system(&dir, &dir).unwrap());
+        assert!(free_space("missing_dir/file").is_err());
+    }
+
+    #[test]
+    fn test_can_punch_holes() {
+        assert!(!can_punch_holes("missing_dir"));
+        // tmpfs punches holes since Linux 3.5
+        if cfg!(target_os = "linux") && Path::new("/dev/shm").is_dir() {
+            assert!(can_punch_holes("/dev/shm"));
+        }
+
+        let dir = std::env::temp_dir().join(format!("punch_probe_{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        can_punch_holes(&dir.to_string_lossy());
+        // the scratch file is removed whatever the result
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(dir).unwrap();
+    }
+}
@@ -0,0 +1,360 @@
+use crate::float::NanPolicy;
+use crate::record::{KeySpec, MissingPolicy};
+use rand::distributions::{Alphanumeric, Distribution, Uniform};
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ElementType {
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+    String,
+    Record,
+    Json,
+}
+
+impl FromStr for ElementType {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<ElementType, Self::Err> {
+        match input {
+            "u8" => Ok(ElementType::U8),
+            "u16" => Ok(ElementType::U16),
+            "u32" => Ok(ElementType::U32),
+            "u64" => Ok(ElementType::U64),
+            "i8" => Ok(ElementType::I8),
+            "i16" => Ok(ElementType::I16),
+            "i32" => Ok(ElementType::I32),
+            "i64" => Ok(ElementType::I64),
+            "f32" => Ok(ElementType::F32),
+            "f64" => Ok(ElementType::F64),
+            "string" => Ok(ElementType::String),
+            "record" => Ok(ElementType::Record),
+            "json" => Ok(ElementType::Json),
+            _ => Err("Wrong value"),
+        }
+    }
+}
+
+/// Calls a generic function with the type parameter selected by an `ElementType`
+#[macro_export]
+macro_rules! dispatch_element_type {
+    ($element_type:expr, $func:ident($($arg:expr),*)) => {
+        match $element_type {
+            $crate::element::ElementType::U8 => $func::<u8>($($arg),*),
+            $crate::element::ElementType::U16 => $func::<u16>($($arg),*),
+            $crate::element::ElementType::U32 => $func::<u32>($($arg),*),
+            $crate::element::ElementType::U64 => $func::<u64>($($arg),*),
+            $crate::element::ElementType::I8 => $func::<i8>($($arg),*),
+            $crate::element::ElementType::I16 => $func::<i16>($($arg),*),
+            $crate::element::ElementType::I32 => $func::<i32>($($arg),*),
+            $crate::element::ElementType::I64 => $func::<i64>($($arg),*),
+            $crate::element::ElementType::F32 => $func::<$crate::float::TotalFloat<f32>>($($arg),*),
+            $crate::element::ElementType::F64 => $func::<$crate::float::TotalFloat<f64>>($($arg),*),
+            $crate::element::ElementType::String => $func::<String>($($arg),*),
+            $crate::element::ElementType::Record => $func::<$crate::record::Record>($($arg),*),
+            $crate::element::ElementType::Json => $func::<$crate::json::JsonRecord>($($arg),*),
+        }
+    };
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The token is not a value of the element type
+    Invalid,
+    /// The token is a value that the parse options do not accept
+    Rejected(&'static str),
+    /// A token of the input failed to parse, at a byte offset of the input
+    Token { offset: u64, token: String, element_type: &'static str, source: Box<ParseError> },
+}
+
+/// Bytes of a failed token kept in its error, which can be a whole line
+const ERROR_TOKEN_LENGTH: usize = 64;
+
+impl ParseError {
+    /// Locates the error of parsing a token at an offset of the data holding it
+    pub(crate) fn at<T: Element>(self, offset: usize, token: &[u8]) -> ParseError {
+        let token = String::from_utf8_lossy(&token[..usize::min(token.len(), ERROR_TOKEN_LENGTH)]).into_owned();
+        ParseError::Token { offset: offset as u64, token, element_type: T::NAME, source: Box::new(self) }
+    }
+
+    /// Moves the offset of the error by the position of the parsed data in the input
+    pub(crate) fn shifted(self, base: u64) -> ParseError {
+        match self {
+            ParseError::Token { offset, token, element_type, source } => ParseError::Token { offset: base + offset, token, element_type, source },
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Invalid => write!(f, "invalid value"),
+            ParseError::Rejected(reason) => write!(f, "{}", reason),
+            ParseError::Token { offset, token, element_type, source } => match **source {
+                ParseError::Invalid => {
+                    write!(f, "`{}` at byte {} is not a value of type {} (the type is set with --type)", token, offset, element_type)
+                }
+                _ => write!(f, "`{}` at byte {}: {}", token, offset, source),
+            },
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Options given on the command line that affect how the values are read.
+/// Every element type uses only the ones that concern it.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    pub nan_policy: NanPolicy,
+    pub keys: Vec<KeySpec>,
+    pub field_separator: Option<char>,
+    pub missing_policy: MissingPolicy,
+}
+
+pub trait Element: Sized {
+    /// Name of the type given with `--type`
+    const NAME: &'static str;
+
+    /// Whether a value takes a whole line instead of a single whitespace separated token
+    const WHOLE_LINE: bool = false;
+
+    fn parse(token: &str, options: &ParseOptions) -> Result<Self, ParseError>;
+
+    /// Rejects the options that can't apply to the type, like keys of another kind, before any value is parsed
+    fn check_options(_options: &ParseOptions) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Heap bytes owned by the value, besides its own size
+    fn heap_size(&self) -> usize {
+        0
+    }
+
+    /// Parses a token straight from the bytes of a file
+    fn parse_bytes(token: &[u8], options: &ParseOptions) -> Result<Self, ParseError> {
+        match std::str::from_utf8(token) {
+            Ok(token) => Self::parse(token, options),
+            Err(_) => Err(ParseError::Invalid),
+        }
+    }
+}
+
+/// Streaming tokenizer splitting the data into the tokens holding single values, without copying them
+pub struct Tokens<'a> {
+    data: &'a [u8],
+    whole_line: bool,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        loop {
+            if self.data.is_empty() {
+                return None;
+            }
+            let is_delimiter = |byte: &u8| if self.whole_line { *byte == b'\n' } else { byte.is_ascii_whitespace() };
+            let end = self.data.iter().position(is_delimiter).unwrap_or(self.data.len());
+            let token = &self.data[..end];
+            self.data = &self.data[usize::min(end + 1, self.data.len())..];
+
+            let token = if self.whole_line { token.strip_suffix(b"\r").unwrap_or(token) } else { token };
+            if !token.is_empty() {
+                return Some(token);
+            }
+        }
+    }
+}
+
+pub fn tokens<T: Element>(data: &[u8]) -> Tokens<'_> {
+    Tokens { data, whole_line: T::WHOLE_LINE }
+}
+
+/// Strips a line read from a file down to the token it holds
+pub fn line_token<T: Element>(line: &[u8]) -> &[u8] {
+    if T::WHOLE_LINE {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        line.strip_suffix(b"\r").unwrap_or(line)
+    } else {
+        line.trim_ascii()
+    }
+}
+
+/// Writes a value as text straight to the output, without building a `String` for it first
+pub trait Format {
+    fn format_to<W: Write>(&self, output: &mut W) -> io::Result<()>;
+}
+
+macro_rules! impl_element_integer {
+    ($($t:ty),*) => {
+        $(
+            impl Element for $t {
+                const NAME: &'static str = stringify!($t);
+
+                fn parse(token: &str, options: &ParseOptions) -> Result<Self, ParseError> {
+                    Self::parse_bytes(token.as_bytes(), options)
+                }
+
+                fn parse_bytes(token: &[u8], _: &ParseOptions) -> Result<Self, ParseError> {
+                    let (negative, digits) = match token {
+                        [b'-', digits @ ..] if <$t>::MIN != 0 => (true, digits),
+                        [b'+', digits @ ..] => (false, digits),
+                        _ => (false, token),
+                    };
+                    if digits.is_empty() {
+                        return Err(ParseError::Invalid);
+                    }
+
+                    let mut value: $t = 0;
+                    for &byte in digits {
+                        let digit = byte.wrapping_sub(b'0');
+                        if digit > 9 {
+                            return Err(ParseError::Invalid);
+                        }
+                        let shifted = value.checked_mul(10);
+                        let next = if negative {
+                            shifted.and_then(|value| value.checked_sub(digit as $t))
+                        } else {
+                            shifted.and_then(|value| value.checked_add(digit as $t))
+                        };
+                        value = next.ok_or(ParseError::Invalid)?;
+                    }
+                    Ok(value)
+                }
+            }
+
+            impl Format for $t {
+                fn format_to<W: Write>(&self, output: &mut W) -> io::Result<()> {
+                    output.write_all(itoa::Buffer::new().format(*self).as_bytes())
+                }
+            }
+        )*
+    };
+}
+
+impl_element_integer!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl Element for String {
+    const NAME: &'static str = "string";
+
+    fn parse(token: &str, _: &ParseOptions) -> Result<Self, ParseError> {
+        Ok(token.to_string())
+    }
+
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl Format for String {
+    fn format_to<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        output.write_all(self.as_bytes())
+    }
+}
+
+pub trait Random: Sized {
+    fn random_vec(count: usize) -> Vec<Self>;
+}
+
+macro_rules! impl_random_integer {
+    ($($t:ty),*) => {
+        $(
+            impl Random for $t {
+                fn random_vec(count: usize) -> Vec<Self> {
+                    Uniform::new_inclusive(<$t>::MIN, <$t>::MAX)
+                        .sample_iter(&mut rand::thread_rng())
+                        .take(count)
+                        .collect()
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_random_float {
+    ($($t:ty),*) => {
+        $(
+            impl Random for $t {
+                fn random_vec(count: usize) -> Vec<Self> {
+                    Uniform::new(-1.0e9, 1.0e9)
+                        .sample_iter(&mut rand::thread_rng())
+                        .take(count)
+                        .collect()
+                }
+            }
+        )*
+    };
+}
+
+impl_random_integer!(u8, u16, u32, u64, i8, i16, i32, i64);
+impl_random_float!(f32, f64);
+
+impl Random for String {
+    fn random_vec(count: usize) -> Vec<Self> {
+        let mut rng = rand::thread_rng();
+        let lengths = Uniform::new_inclusive(1, 16);
+        (0..count)
+            .map(|_| {
+                let length = lengths.sample(&mut rng);
+                Alphanumeric
+                    .sample_iter(&mut rng)
+                    .take(length)
+                    .map(char::from)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_integer_bytes() {
+        let tokens = ["0", "+17", "-0", "-128", "127", "128", "-129", "", "-", "+", "1a", " 1", "255"];
+        for token in tokens {
+            let options = ParseOptions::default();
+            assert_eq!(i8::parse_bytes(token.as_bytes(), &options).ok(), token.parse::<i8>().ok(), "{}", token);
+            assert_eq!(u8::parse_bytes(token.as_bytes(), &options).ok(), token.parse::<u8>().ok(), "{}", token);
+        }
+        assert_eq!(i64::parse("-9223372036854775808", &ParseOptions::default()), Ok(i64::MIN));
+        assert_eq!(u64::parse("18446744073709551616", &ParseOptions::default()), Err(ParseError::Invalid));
+    }
+
+    #[test]
+    fn test_format_integer() {
+        for value in [0, 7, -1, i64::MIN, i64::MAX] {
+            let mut output = Vec::new();
+            value.format_to(&mut output).unwrap();
+            assert_eq!(output, value.to_string().as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_tokens() {
+        let data = b"1 2\t3\r\n\n a b\n";
+        assert_eq!(tokens::<u32>(data).collect::<Vec<_>>(), vec![&b"1"[..], b"2", b"3", b"a", b"b"]);
+        assert_eq!(tokens::<crate::record::Record>(data).collect::<Vec<_>>(), vec![&b"1 2\t3"[..], b" a b"]);
+    }
+
+    #[test]
+    fn test_element_type_from_str() {
+        assert_eq!("i64".parse::<ElementType>(), Ok(ElementType::I64));
+        assert_eq!("string".parse::<ElementType>(), Ok(ElementType::String));
+        assert!("u128".parse::<ElementType>().is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use crate::cancel::CancelToken;
+use crate::element::{line_token, Element, Format, ParseError, ParseOptions};
+use crate::error::SortError;
+use crate::file_reader;
+use crate::memory::Sample;
+use crate::thread_pool::{Channel, ThreadPool};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufRead, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// Format of the values in the files created while sorting
+pub trait Encoding<T>: Clone + Send + Sync + 'static {
+    /// Reads all values held in a buffer, e.g. a chunk of a file
+    fn decode(&self, data: &[u8]) -> Result<Vec<T>, ParseError>;
+
+    /// Reads all values held in a byte range of a file that fits in RAM
+    fn load(&self, path: &str, range: Range<u64>) -> Result<Vec<T>, SortError> {
+        let data = file_reader::map_file(path).map_err(SortError::io(path))?;
+        self.decode(&data[range.start as usize..range.end as usize])
+            .map_err(|err| SortError::parse(path)(err.shifted(range.start)))
+    }
+
+    /// Reads all values held in a byte range of a file that fits in RAM,
+    /// parsing parts of it on the workers of the pool when the encoding can, which stop once `cancel` is cancelled
+    fn load_par(&self, path: &str, range: Range<u64>, _pool: &Arc<Mutex<ThreadPool<()>>>, _cancel: &CancelToken) -> Result<Vec<T>, SortError>
+    where
+        T: Channel,
+    {
+        self.load(path, range)
+    }
+
+    /// Decodes a sample of the data to measure the heap bytes taken by its values
+    fn sample(&self, data: &[u8]) -> Result<Sample, ParseError>;
+
+    /// Finds the first position of a value in the range that is not before `position`, or the end of the range
+    fn split_point<R: BufRead + Seek>(&self, input: &mut R, range: &Range<u64>, position: u64) -> io::Result<u64>;
+
+    /// Writes all values to a new file
+    fn store(&self, path: &str, data: &[T]) -> Result<(), SortError>;
+
+    /// Writes all values to a new file, using the workers of the pool when the encoding can, which stop once `cancel` is cancelled
+    fn store_par(&self, path: &str, data: Vec<T>, _pool: &Arc<Mutex<ThreadPool<()>>>, _cancel: &CancelToken) -> Result<(), SortError>
+    where
+        T: Channel,
+    {
+        self.store(path, &data)
+    }
+
+    /// Reads a single value, `None` at the end of input. Values that can't be decoded are `InvalidData` errors.
+    /// The buffer is reused between the calls to avoid allocating for every value.
+    fn read_next<R: BufRead>(&self, input: &mut R, buffer: &mut Vec<u8>) -> io::Result<Option<T>>;
+
+    fn write_next<W: Write>(&self, output: &mut W, value: &T) -> io::Result<()>;
+
+    /// Copies a single value without decoding it, returns `false` at the end of input
+    fn copy_next<R: BufRead, W: Write>(&self, input: &mut R, output: &mut W, buffer: &mut Vec<u8>) -> io::Result<bool>;
+}
+
+/// Values written as text, one per line
+#[derive(Debug, Clone, Default)]
+pub struct TextEncoding {
+    pub options: ParseOptions,
+}
+
+impl<T: Element + Format> Encoding<T> for TextEncoding {
+    fn decode(&self, data: &[u8]) -> Result<Vec<T>, ParseError> {
+        file_reader::read_from_bytes::<T>(data, &self.options)
+    }
+
+    fn sample(&self, data: &[u8]) -> Result<Sample, ParseError> {
+        let values: Vec<T> = self.decode(data)?;
+        let heap = values.capacity() * mem::size_of::<T>() + values.iter().map(Element::heap_size).sum::<usize>();
+        Ok(Sample { bytes: data.len(), values: values.len(), heap })
+    }
+
+    fn load_par(&self, path: &str, range: Range<u64>, pool: &Arc<Mutex<ThreadPool<()>>>, cancel: &CancelToken) -> Result<Vec<T>, SortError>
+    where
+        T: Channel,
+    {
+        let data = file_reader::map_file(path).map_err(SortError::io(path))?;
+        file_reader::read_from_bytes_par::<T>(Arc::new(data), range.start as usize..range.end as usize, &self.options, pool, cancel)
+            .map_err(SortError::parse(path))?
+            .ok_or(SortError::Cancelled)
+    }
+
+    fn split_point<R: BufRead + Seek>(&self, input: &mut R, range: &Range<u64>, position: u64) -> io::Result<u64> {
+        if position <= range.start {
+            return Ok(range.start);
+        }
+        // a value starts right after the new line ending the line that holds the previous byte
+        input.seek(SeekFrom::Start(position - 1))?;
+        let skipped = input.skip_until(b'\n')?;
+        Ok(u64::min(position - 1 + skipped as u64, range.end))
+    }
+
+    fn store(&self, path: &str, data: &[T]) -> Result<(), SortError> {
+        file_reader::write_from_vec(path, data, "\n")
+    }
+
+    fn store_par(&self, path: &str, data: Vec<T>, pool: &Arc<Mutex<ThreadPool<()>>>, cancel: &CancelToken) -> Result<(), SortError>
+    where
+        T: Channel,
+    {
+        file_reader::write_from_vec_par(path, data, "\n", pool, cancel)
+    }
+
+    fn read_next<R: BufRead>(&self, input: &mut R, buffer: &mut Vec<u8>) -> io::Result<Option<T>> {
+        buffer.clear();
+        if input.read_until(b'\n', buffer)? == 0 {
+            return Ok(None);
+        }
+        match T::parse_bytes(line_token::<T>(buffer), &self.options) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        }
+    }
+
+    fn write_next<W: Write>(&self, output: &mut W, value: &T) -> io::Result<()> {
+        value.format_to(output)?;
+        output.write_all(b"\n")
+    }
+
+    fn copy_next<R: BufRead, W: Write>(&self, input: &mut R, output: &mut W, buffer: &mut Vec<u8>) -> io::Result<bool> {
+        buffer.clear();
+        if input.read_until(b'\n', buffer)? == 0 {
+            return Ok(false);
+        }
+        if !buffer.ends_with(b"\n") {
+            buffer.push(b'\n');
+        }
+        output.write_all(buffer)?;
+        Ok(true)
+    }
+}
+
+/// Bytes between the offsets of values kept by an indexed `BinaryEncoding`
+pub(crate) const INDEX_INTERVAL: u64 = 1 << 16;
+
+/// Values serialized with bincode, each one preceded by its length as a little endian `u32`
+#[derive(Debug)]
+pub struct BinaryEncoding<T> {
+    /// Offsets of values of the file being sorted, about `INDEX_INTERVAL` bytes apart, in increasing order
+    offsets: Arc<Vec<u64>>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> BinaryEncoding<T> {
+    pub fn new() -> BinaryEncoding<T> {
+        BinaryEncoding { offsets: Arc::new(Vec::new()), marker: PhantomData }
+    }
+
+    /// Encoding of a file where values start at `offsets`, which `split_point` goes through the lengths from
+    pub(crate) fn indexed(offsets: Vec<u64>) -> BinaryEncoding<T> {
+        BinaryEncoding { offsets: Arc::new(offsets), marker: PhantomData }
+    }
+
+    /// Reads the length of the next value, `None` at the end of input.
+    /// A length cut by the end of input is an `UnexpectedEof` error, the file having been truncated.
+    fn read_length<R: Read>(input: &mut R) -> io::Result<Option<usize>> {
+        let mut length = [0_u8; 4];
+        let mut read = 0;
+        while read < length.len() {
+            match input.read(&mut length[read..]) {
+                Ok(0) => break,
+                Ok(count) => read += count,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        match read {
+            0 => Ok(None),
+            4 => Ok(Some(u32::from_le_bytes(length) as usize)),
+            _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        }
+    }
+}
+
+impl<T> Default for BinaryEncoding<T> {
+    fn default() -> Self {
+        BinaryEncoding::new()
+    }
+}
+
+impl<T> Clone for BinaryEncoding<T> {
+    fn clone(&self) -> Self {
+        BinaryEncoding { offsets: Arc::clone(&self.offsets), marker: PhantomData }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + 'static> Encoding<T> for BinaryEncoding<T> {
+    fn decode(&self, mut data: &[u8]) -> Result<Vec<T>, ParseError> {
+        let mut result = Vec::new();
+        let mut buffer = Vec::new();
+        // the values were written by the sort, so anything that can't be read back is invalid
+        while let Some(value) = self.read_next(&mut data, &mut buffer).map_err(|_| ParseError::Invalid)? {
+            result.push(value);
+        }
+        Ok(result)
+    }
+
+    fn sample(&self, data: &[u8]) -> Result<Sample, ParseError> {
+        let values = self.decode(data)?;
+        // the heap data owned by the values is assumed to take about as many bytes as their encoding
+        Ok(Sample { bytes: data.len(), values: values.len(), heap: values.capacity() * mem::size_of::<T>() + data.len() })
+    }
+
+    fn split_point<R: BufRead + Seek>(&self, input: &mut R, range: &Range<u64>, position: u64) -> io::Result<u64> {
+        // values can only be found by going through the lengths, from the last known value before the position
+        let known = self.offsets.partition_point(|&offset| offset <= position);
+        let mut point = self.offsets[..known].last().map_or(range.start, |&offset| u64::max(offset, range.start));
+        input.seek(SeekFrom::Start(point))?;
+        while point < position && point < range.end {
+            let length = BinaryEncoding::<T>::read_length(input)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            input.seek_relative(length as i64)?;
+            point += 4 + length as u64;
+        }
+        Ok(point)
+    }
+
+    fn store(&self, path: &str, data: &[T]) -> Result<(), SortError> {
+        let file = OpenOptions::new().truncate(true).create(true).write(true).open(path).map_err(SortError::io(path))?;
+        let mut output = BufWriter::new(file);
+        for value in data {
+            self.write_next(&mut output, value).map_err(SortError::io(path))?;
+        }
+        output.flush().map_err(SortError::io(path))
+    }
+
+    fn read_next<R: BufRead>(&self, input: &mut R, buffer: &mut Vec<u8>) -> io::Result<Option<T>> {
+        let length = match BinaryEncoding::<T>::read_length(input)? {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+        buffer.resize(length, 0);
+        input.read_exact(buffer)?;
+        bincode::deserialize(buffer).map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn write_next<W: Write>(&self, output: &mut W, value: &T) -> io::Result<()> {
+        let buffer = bincode::serialize(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let length = u32::try_from(buffer.len()).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        output.write_all(&length.to_le_bytes())?;
+        output.write_all(&buffer)
+    }
+
+    fn copy_next<R: BufRead, W: Write>(&self, input: &mut R, output: &mut W, _: &mut Vec<u8>) -> io::Result<bool> {
+        let length = match BinaryEncoding::<T>::read_length(input)? {
+            Some(length) => length,
+            None => return Ok(false),
+        };
+        output.write_all(&(length as u32).to_le_bytes())?;
+        let copied = io::copy(&mut input.take(length as u64), output)?;
+        if copied != length as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Event {
+        id: u64,
+        name: String,
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let encoding = BinaryEncoding::<Event>::new();
+        let events = vec![Event { id: 7, name: "a".to_string() }, Event { id: 1, name: String::new() }];
+
+        let mut encoded = Vec::new();
+        for event in &events {
+            encoding.write_next(&mut encoded, event).unwrap();
+        }
+
+        let mut copied = Vec::new();
+        let mut buffer = Vec::new();
+        let mut input = encoded.as_slice();
+        while encoding.copy_next(&mut input, &mut copied, &mut buffer).unwrap() {}
+        assert_eq!(copied, encoded);
+
+        let mut input = copied.as_slice();
+        assert_eq!(encoding.read_next(&mut input, &mut buffer).unwrap(), Some(events[0].clone()));
+        assert_eq!(encoding.read_next(&mut input, &mut buffer).unwrap(), Some(events[1].clone()));
+        assert_eq!(encoding.read_next(&mut input, &mut buffer).unwrap(), None);
+        assert!(encoding.read_next(&mut &encoded[..6], &mut buffer).is_err());
+        // a length cut by the end of the file isn't taken for the end of the values
+        let first_length = 4 + bincode::serialized_size(&events[0]).unwrap() as usize;
+        let mut truncated = &encoded[..first_length + 2];
+        assert_eq!(encoding.read_next(&mut truncated, &mut buffer).unwrap(), Some(events[0].clone()));
+        assert_eq!(encoding.read_next(&mut truncated, &mut buffer).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(encoding.copy_next(&mut &encoded[..2], &mut copied, &mut buffer).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_indexed_split_point() {
+        let encoding = BinaryEncoding::<Event>::new();
+        let mut encoded = Vec::new();
+        let mut offsets = Vec::new();
+        for id in 0..1000 {
+            offsets.push(encoded.len() as u64);
+            encoding.write_next(&mut encoded, &Event { id, name: "x".repeat(id as usize % 7) }).unwrap();
+        }
+        let indexed = BinaryEncoding::<Event>::indexed(offsets.iter().copied().step_by(10).collect());
+
+        let mut input = io::Cursor::new(&encoded);
+        for range in [0..encoded.len() as u64, offsets[15]..offsets[600], offsets[3]..offsets[4]] {
+            for position in [range.start, range.start + 1, (range.start + range.end) / 2, range.end - 1] {
+                let point = indexed.split_point(&mut input, &range, position).unwrap();
+                assert_eq!(point, encoding.split_point(&mut input, &range, position).unwrap());
+                assert!(point == range.end || offsets.contains(&point));
+            }
+        }
+    }
+}
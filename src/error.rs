@@ -0,0 +1,63 @@
+use crate::element::ParseError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Failure of a sort. The output is never written when it happens.
+#[derive(Debug)]
+pub enum SortError {
+    /// Reading or writing a file failed
+    Io { path: String, source: io::Error },
+    /// A value of a file is rejected by the parse options
+    Parse { path: String, source: ParseError },
+    /// The parse options can't apply to the type of the values
+    InvalidOptions(String),
+    /// The output would replace the input without sorting in place being asked
+    OutputIsInput { path: String },
+    /// The file system of the path doesn't have the free space the sort is estimated to need
+    NotEnoughSpace { path: String, needed: u64, available: u64 },
+    /// The temporary files would take more bytes than allowed
+    TempQuota { needed: u64, limit: u64 },
+    /// The sort was stopped by its `CancelToken`
+    Cancelled,
+}
+
+impl SortError {
+    /// Error of an I/O operation on the file, to be given to `map_err`
+    pub fn io(path: &str) -> impl Fn(io::Error) -> SortError + '_ {
+        move |source| SortError::Io { path: path.to_string(), source }
+    }
+
+    /// Error of parsing the values of the file, to be given to `map_err`
+    pub fn parse(path: &str) -> impl Fn(ParseError) -> SortError + '_ {
+        move |source| SortError::Parse { path: path.to_string(), source }
+    }
+}
+
+impl fmt::Display for SortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortError::Io { path, source } => write!(f, "Couldn't read or write the file {}: {}", path, source),
+            SortError::Parse { path, source } => write!(f, "Couldn't parse the file {}: {}", path, source),
+            SortError::InvalidOptions(reason) => write!(f, "{}", reason),
+            SortError::OutputIsInput { path } => write!(f, "The output {} is the input, which is only replaced when sorting in place", path),
+            SortError::NotEnoughSpace { path, needed, available } => {
+                write!(f, "The sort needs about {} bytes on the file system of {}, only {} are free", needed, path, available)
+            }
+            SortError::TempQuota { needed, limit } => {
+                write!(f, "The temporary files need {} bytes, more than the {} bytes allowed", needed, limit)
+            }
+            SortError::Cancelled => write!(f, "The sort was cancelled"),
+        }
+    }
+}
+
+impl Error for SortError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SortError::Io { source, .. } => Some(source),
+            SortError::Parse { source, .. } => Some(source),
+            SortError::InvalidOptions(_) | SortError::OutputIsInput { .. } | SortError::NotEnoughSpace { .. } | SortError::TempQuota { .. } | SortError::Cancelled => None,
+        }
+    }
+}
@@ -1,115 +1,355 @@
-use rand::distributions::Distribution;
-use rand::distributions::Uniform;
-use std::fs::{File, OpenOptions};
-use std::io;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
-use std::str::FromStr;
-
-pub fn load_file(file_path: &str) -> String {
-    let file = File::open(file_path);
-    match file {
-        Ok(file) => {
-            let mut buffer = String::new();
-            let mut reader = BufReader::new(file);
-            match reader.read_to_string(&mut buffer) {
-                Ok(_) => buffer,
-                Err(_) => String::new(),
-            }
-        }
-        Err(_) => String::new(),
-    }
-}
-
-pub fn load_file_to_vec<T: FromStr>(file_path: &str) -> Vec<T> {
-    read_from_string(&load_file(file_path))
-}
-
-pub fn read_from_string<T: FromStr>(data: &str) -> Vec<T> {
-    let mut result = Vec::<T>::new();
-    for number in data.split_whitespace() {
-        match number.parse::<T>() {
-            Ok(value) => result.push(value),
-            _ => {}
-        }
-    }
-    result
-}
-
-pub fn write_from_vec<T: ToString>(
-    file_path: &str,
-    data: &[T],
-    delimiter: &str,
-) -> io::Result<()> {
-    let file = OpenOptions::new()
-        .truncate(true)
-        .create(true)
-        .write(true)
-        .open(file_path);
-
-    if file.is_err() {
-        return Err(file.err().unwrap());
-    }
-
-    let mut output = BufWriter::with_capacity(100000000, file.unwrap());
-
-    for elem in data {
-        output.write((elem.to_string() + delimiter).as_bytes())?;
-    }
-
-    Ok(())
-}
-
-pub fn write_random_data(file_path: &str, numbers_count: usize) -> io::Result<()> {
-    let file = OpenOptions::new()
-        .truncate(true)
-        .create(true)
-        .write(true)
-        .open(file_path);
-
-    if file.is_err() {
-        return Err(file.err().unwrap());
-    }
-
-    const AVERAGE_BYTES_PER_NUMBER: usize = 3 * 4;
-    const CHUNK_SIZE_IN_BYTES: usize = 500_000_000;
-    let iters_count = usize::max(
-        1,
-        numbers_count * AVERAGE_BYTES_PER_NUMBER / CHUNK_SIZE_IN_BYTES,
-    );
-    let number_count_per_iter = numbers_count / iters_count;
-
-    let mut writer = BufWriter::new(file.unwrap());
-
-    for _ in 0..iters_count {
-        let numbers: Vec<String> = Uniform::new(u32::MIN, u32::MAX)
-            .sample_iter(&mut rand::thread_rng())
-            .take(number_count_per_iter)
-            .map(|number| number.to_string())
-            .collect();
-
-        let result = writer.write_all(numbers.join("\n").as_bytes());
-        if result.is_err() {
-            return Err(result.err().unwrap());
-        }
-    }
-    Ok(())
-}
-
-pub fn get_lines_count(path: &str) -> io::Result<usize> {
-    let new_file = File::open(&path)?;
-    Ok(BufReader::new(new_file).lines().count())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_read_from_string() {
-        let data = "5\n7\t2a12 6 3 7 167 3\n7";
-        assert_eq!(
-            read_from_string::<u32>(data),
-            vec![5, 7, 6, 3, 7, 167, 3, 7]
-        );
-    }
-}
+use crate::cancel::CancelToken;
+use crate::element::{tokens, Element, Format, ParseError, ParseOptions, Random};
+use crate::error::SortError;
+use crate::thread_pool::{Channel, ThreadPool};
+use memmap2::Mmap;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::{Deref, Range};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// Path standing for the standard input or output on the command line
+pub const STDIO_PATH: &str = "-";
+
+/// Contents of an input file. Regular files are memory mapped, so they are parsed
+/// without being copied to the heap first. Pipes and files that can't be mapped are read into a buffer.
+pub enum InputData {
+    Mapped(Mmap),
+    Buffered(Vec<u8>),
+}
+
+impl Deref for InputData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputData::Mapped(mmap) => mmap,
+            InputData::Buffered(buffer) => buffer,
+        }
+    }
+}
+
+pub fn map_file(file_path: &str) -> io::Result<InputData> {
+    let file = File::open(file_path)?;
+    let metadata = file.metadata()?;
+    if metadata.is_file() && metadata.len() > 0 {
+        // SAFETY: the input and temporary files are not modified while they are being sorted
+        if let Ok(mmap) = unsafe { Mmap::map(&file) } {
+            return Ok(InputData::Mapped(mmap));
+        }
+    }
+
+    let mut buffer = Vec::new();
+    BufReader::new(file).read_to_end(&mut buffer)?;
+    Ok(InputData::Buffered(buffer))
+}
+
+/// Parses a range of the data like `read_from_bytes`, but the range is split at delimiters into a slice per worker of the pool
+/// and the slices are parsed concurrently, the current thread taking the last one.
+/// The values keep the order of the data. `None` is returned once `cancel` is cancelled.
+pub fn read_from_bytes_par<T: Element + Channel>(data: Arc<InputData>, range: Range<usize>, options: &ParseOptions, pool: &Arc<Mutex<ThreadPool<()>>>, cancel: &CancelToken) -> Result<Option<Vec<T>>, ParseError> {
+    // the jobs are sent while the pool is still locked, so each of them gets one of the available workers
+    let locked_pool = pool.lock().unwrap();
+    let parts = locked_pool.available_workers() + 1;
+    let mut ranges = split_at_delimiters::<T>(&data[range.clone()], parts);
+    let last = ranges.pop().unwrap_or_default();
+    let offset = range.start;
+
+    let mut pending = Vec::with_capacity(ranges.len());
+    for part in ranges {
+        // the job is only skipped by a pool once the token is cancelled, leaving no values
+        let parsed = Arc::new(Mutex::new(Ok(None)));
+        let parsed_copy = Arc::clone(&parsed);
+        let data = Arc::clone(&data);
+        let options = options.clone();
+        let cancel = cancel.clone();
+        let result = locked_pool.execute(move || {
+            *parsed_copy.lock().unwrap() = read_chunks::<T>(&data[offset + part.start..offset + part.end], offset + part.start, &options, &cancel)
+        });
+        pending.push((result, parsed));
+    }
+    drop(locked_pool);
+    let last = read_chunks::<T>(&data[offset + last.start..offset + last.end], offset + last.start, options, cancel);
+
+    // every job is waited for before an error is returned, their workers sending back when they are done
+    let mut parts = Vec::with_capacity(pending.len() + 1);
+    for (result, parsed) in pending {
+        result.recv().unwrap();
+        parts.push(Arc::into_inner(parsed).unwrap().into_inner().unwrap());
+    }
+    parts.push(last);
+    let Some(chunks) = parts.into_iter().collect::<Result<Option<Vec<_>>, _>>()? else {
+        return Ok(None);
+    };
+
+    // allocated once, so the values aren't moved by a growing `Vec` while the chunks are still held
+    let mut values = Vec::with_capacity(chunks.iter().flatten().map(Vec::len).sum());
+    for chunk in chunks.into_iter().flatten() {
+        values.extend(chunk);
+    }
+    Ok(Some(values))
+}
+
+/// Bytes parsed between two checks of the cancellation
+const PARSE_CHUNK_LENGTH: usize = 1 << 20;
+
+/// Parses the data like `read_from_bytes` a chunk of about `PARSE_CHUNK_LENGTH` bytes at a time,
+/// `None` once `cancel` is cancelled. The data starts at the byte `offset`, which the errors are located from.
+fn read_chunks<T: Element>(data: &[u8], offset: usize, options: &ParseOptions, cancel: &CancelToken) -> Result<Option<Vec<Vec<T>>>, ParseError> {
+    let mut chunks = Vec::new();
+    for chunk in split_at_delimiters::<T>(data, data.len().div_ceil(PARSE_CHUNK_LENGTH)) {
+        if cancel.is_cancelled() {
+            return Ok(None);
+        }
+        chunks.push(read_from_bytes::<T>(&data[chunk.clone()], options).map_err(|err| err.shifted((offset + chunk.start) as u64))?);
+    }
+    Ok(Some(chunks))
+}
+
+/// Splits the data into at most `parts` ranges of similar length, ending right after a delimiter
+/// so that no token is cut in two
+fn split_at_delimiters<T: Element>(data: &[u8], parts: usize) -> Vec<Range<usize>> {
+    let is_delimiter = |byte: &u8| if T::WHOLE_LINE { *byte == b'\n' } else { byte.is_ascii_whitespace() };
+    let mut ranges = Vec::with_capacity(parts);
+    let mut start = 0;
+    for part in 1..parts {
+        let middle = usize::max(start, data.len() * part / parts);
+        let end = match data[middle..].iter().position(is_delimiter) {
+            Some(position) => middle + position + 1,
+            None => data.len(),
+        };
+        if end > start {
+            ranges.push(start..end);
+            start = end;
+        }
+    }
+    ranges.push(start..data.len());
+    ranges
+}
+
+/// Parses all the values of the data, failing at the first token that isn't a value of the element type
+/// with the byte offset of the token in the data
+pub fn read_from_bytes<T: Element>(data: &[u8], options: &ParseOptions) -> Result<Vec<T>, ParseError> {
+    let mut result = Vec::<T>::new();
+    for token in tokens::<T>(data) {
+        match T::parse_bytes(token, options) {
+            Ok(value) => result.push(value),
+            Err(err) => return Err(err.at::<T>(token.as_ptr() as usize - data.as_ptr() as usize, token)),
+        }
+    }
+    // the spare capacity left by growing isn't kept while the values are sorted
+    result.shrink_to_fit();
+    Ok(result)
+}
+
+pub fn write_from_vec<T: Format>(
+    file_path: &str,
+    data: &[T],
+    delimiter: &str,
+) -> Result<(), SortError> {
+    let mut output = OpenOptions::new()
+        .truncate(true)
+        .create(true)
+        .write(true)
+        .open(file_path)
+        .map_err(SortError::io(file_path))?;
+
+    let mut buffer = Vec::new();
+    for chunk in data.chunks(FORMAT_CHUNK_LENGTH) {
+        format_chunk(chunk, delimiter, &mut buffer);
+        output.write_all(&buffer).map_err(SortError::io(file_path))?;
+    }
+    Ok(())
+}
+
+/// Same as `write_from_vec`, but the chunks of values are formatted in parallel by the pool
+/// while the current thread writes the formatted chunks in order.
+/// Chunks are formatted on the current thread when no worker is available.
+/// Fails with `SortError::Cancelled` once `cancel` is cancelled, before the next chunk.
+pub fn write_from_vec_par<T: Format + Channel>(
+    file_path: &str,
+    data: Vec<T>,
+    delimiter: &str,
+    pool: &Arc<Mutex<ThreadPool<()>>>,
+    cancel: &CancelToken,
+) -> Result<(), SortError> {
+    let mut output = OpenOptions::new()
+        .truncate(true)
+        .create(true)
+        .write(true)
+        .open(file_path)
+        .map_err(SortError::io(file_path))?;
+
+    let max_pending = 2 * pool.lock().unwrap().size();
+    let data = Arc::new(data);
+    let delimiter = Arc::new(delimiter.to_string());
+    let mut pending = VecDeque::with_capacity(max_pending);
+    let mut buffers = Vec::<Vec<u8>>::new();
+
+    for start in (0..data.len()).step_by(FORMAT_CHUNK_LENGTH) {
+        cancel.check()?;
+        if pending.len() == max_pending {
+            let buffer = pending.pop_front().map(FormattedChunk::wait).unwrap();
+            output.write_all(&buffer).map_err(SortError::io(file_path))?;
+            buffers.push(buffer);
+        }
+
+        let end = usize::min(start + FORMAT_CHUNK_LENGTH, data.len());
+        let mut buffer = buffers.pop().unwrap_or_default();
+        let pool = pool.lock().unwrap();
+        if pool.is_available() {
+            let formatted = Arc::new(Mutex::new(buffer));
+            let formatted_copy = Arc::clone(&formatted);
+            let data = Arc::clone(&data);
+            let delimiter = Arc::clone(&delimiter);
+            let result = pool.execute(move || format_chunk(&data[start..end], &delimiter, &mut formatted_copy.lock().unwrap()));
+            pending.push_back(FormattedChunk::Pending(result, formatted));
+        } else {
+            drop(pool);
+            format_chunk(&data[start..end], &delimiter, &mut buffer);
+            pending.push_back(FormattedChunk::Done(buffer));
+        }
+    }
+
+    for chunk in pending {
+        output.write_all(&chunk.wait()).map_err(SortError::io(file_path))?;
+    }
+    // the jobs are only skipped by a pool once the token is cancelled, leaving their chunks unformatted
+    cancel.check()
+}
+
+/// Values formatted at once before being written
+pub const FORMAT_CHUNK_LENGTH: usize = 1 << 16;
+
+enum FormattedChunk {
+    Pending(mpsc::Receiver<()>, Arc<Mutex<Vec<u8>>>),
+    Done(Vec<u8>),
+}
+
+impl FormattedChunk {
+    fn wait(self) -> Vec<u8> {
+        match self {
+            FormattedChunk::Pending(result, buffer) => {
+                result.recv().unwrap();
+                Arc::into_inner(buffer).unwrap().into_inner().unwrap()
+            }
+            FormattedChunk::Done(buffer) => buffer,
+        }
+    }
+}
+
+fn format_chunk<T: Format>(values: &[T], delimiter: &str, buffer: &mut Vec<u8>) {
+    buffer.clear();
+    for value in values {
+        // writing to a `Vec` never fails
+        let _ = value.format_to(buffer);
+        buffer.extend_from_slice(delimiter.as_bytes());
+    }
+}
+
+pub fn write_random_data<T: Random + Format>(file_path: &str, numbers_count: usize) -> Result<(), SortError> {
+    let file = OpenOptions::new()
+        .truncate(true)
+        .create(true)
+        .write(true)
+        .open(file_path)
+        .map_err(SortError::io(file_path))?;
+    write_random_to::<T, _>(file, file_path, numbers_count)
+}
+
+/// Same as `write_random_data`, for any output like the standard output
+pub fn write_random_to<T: Random + Format, W: Write>(output: W, output_name: &str, numbers_count: usize) -> Result<(), SortError> {
+    const AVERAGE_BYTES_PER_NUMBER: usize = 3 * 4;
+    const CHUNK_SIZE_IN_BYTES: usize = 500_000_000;
+    let iters_count = usize::max(
+        1,
+        numbers_count * AVERAGE_BYTES_PER_NUMBER / CHUNK_SIZE_IN_BYTES,
+    );
+    let number_count_per_iter = numbers_count / iters_count;
+
+    let mut writer = BufWriter::new(output);
+
+    for iter in 0..iters_count {
+        let count = if iter == iters_count - 1 {
+            numbers_count - number_count_per_iter * iter
+        } else {
+            number_count_per_iter
+        };
+        for number in T::random_vec(count) {
+            number.format_to(&mut writer).map_err(SortError::io(output_name))?;
+            writer.write_all(b"\n").map_err(SortError::io(output_name))?;
+        }
+    }
+    writer.flush().map_err(SortError::io(output_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_from_bytes() {
+        let data = b"5\n7\t12 6 3 7 167 3\n7";
+        assert_eq!(
+            read_from_bytes::<u32>(data, &ParseOptions::default()),
+            Ok(vec![5, 7, 12, 6, 3, 7, 167, 3, 7])
+        );
+
+        let err = read_from_bytes::<u32>(b"5\n-3\n2", &ParseOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "`-3` at byte 2 is not a value of type u32 (the type is set with --type)");
+        let err = read_from_bytes::<i64>(b"5 9223372036854775808 abc", &ParseOptions::default()).unwrap_err();
+        assert!(matches!(err, ParseError::Token { offset: 2, .. }), "{}", err);
+    }
+
+    #[test]
+    fn test_split_at_delimiters() {
+        let data = b"12 345\n6 7\n89";
+        assert_eq!(split_at_delimiters::<u32>(data, 3), vec![0..7, 7..9, 9..13]);
+        assert_eq!(split_at_delimiters::<crate::record::Record>(data, 3), vec![0..7, 7..11, 11..13]);
+        assert_eq!(split_at_delimiters::<u32>(data, 20).into_iter().flatten().collect::<Vec<_>>(), (0..13).collect::<Vec<_>>());
+        assert_eq!(split_at_delimiters::<u32>(b"", 4), vec![0..0]);
+    }
+
+    #[test]
+    fn test_read_from_bytes_par() {
+        let values: Vec<i64> = (0..100_000).map(|value| value * 7919 % 100_003 - 50_000).collect();
+        let mut data: Vec<u8> = b"skipped\n".to_vec();
+        for value in &values {
+            data.extend_from_slice(format!("{}\n", value).as_bytes());
+        }
+        let length = data.len();
+        let data = Arc::new(InputData::Buffered(data));
+
+        let pool = Arc::new(Mutex::new(ThreadPool::new(4)));
+        let cancel = CancelToken::new();
+        assert_eq!(read_from_bytes_par::<i64>(Arc::clone(&data), 8..length, &ParseOptions::default(), &pool, &cancel).unwrap(), Some(values));
+        // the offsets of the errors are counted from the start of the data, whichever part holds the token
+        let err = read_from_bytes_par::<i64>(Arc::clone(&data), 0..length, &ParseOptions::default(), &pool, &cancel).unwrap_err();
+        assert!(matches!(err, ParseError::Token { offset: 0, .. }), "{}", err);
+        cancel.cancel();
+        assert_eq!(read_from_bytes_par::<i64>(data, 8..length, &ParseOptions::default(), &pool, &cancel).unwrap(), None);
+    }
+
+    #[test]
+    fn test_write_from_vec_par() {
+        let dir = std::env::temp_dir();
+        let seq_path = dir.join(format!("write_seq_{}", std::process::id())).to_string_lossy().to_string();
+        let par_path = dir.join(format!("write_par_{}", std::process::id())).to_string_lossy().to_string();
+        let data: Vec<i32> = (-200_000..200_000).rev().collect();
+
+        write_from_vec(&seq_path, &data, "\n").unwrap();
+        let pool = Arc::new(Mutex::new(ThreadPool::new(3)));
+        let cancel = CancelToken::new();
+        write_from_vec_par(&par_path, data.clone(), "\n", &pool, &cancel).unwrap();
+        let written = std::fs::read(&par_path).unwrap();
+        assert_eq!(written, std::fs::read(&seq_path).unwrap());
+        assert_eq!(written.iter().filter(|&&byte| byte == b'\n').count(), 400_000);
+        cancel.cancel();
+        assert!(matches!(write_from_vec_par(&par_path, data, "\n", &pool, &cancel), Err(SortError::Cancelled)));
+
+        let _ = std::fs::remove_file(seq_path);
+        let _ = std::fs::remove_file(par_path);
+    }
+}
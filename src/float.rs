@@ -0,0 +1,164 @@
+use crate::element::{Element, Format, ParseError, ParseOptions, Random};
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum NanPolicy {
+    First,
+    #[default]
+    Last,
+    Reject,
+}
+
+impl FromStr for NanPolicy {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<NanPolicy, Self::Err> {
+        match input {
+            "first" => Ok(NanPolicy::First),
+            "last" => Ok(NanPolicy::Last),
+            "reject" => Ok(NanPolicy::Reject),
+            _ => Err("Wrong value"),
+        }
+    }
+}
+
+/// Floating point value ordered by the IEEE 754 totalOrder predicate.
+///
+/// `-0.0` is placed before `0.0` and both keep their sign in the output.
+/// The sign of NaN decides whether it goes before `-inf` or after `inf`,
+/// so parsing sets it according to the `NanPolicy`.
+#[derive(Debug, Clone, Copy)]
+pub struct TotalFloat<F>(pub F);
+
+macro_rules! impl_total_float {
+    ($($t:ty),*) => {
+        $(
+            impl PartialEq for TotalFloat<$t> {
+                fn eq(&self, other: &Self) -> bool {
+                    self.cmp(other) == Ordering::Equal
+                }
+            }
+
+            impl Eq for TotalFloat<$t> {}
+
+            impl PartialOrd for TotalFloat<$t> {
+                fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                    Some(self.cmp(other))
+                }
+            }
+
+            impl Ord for TotalFloat<$t> {
+                fn cmp(&self, other: &Self) -> Ordering {
+                    self.0.total_cmp(&other.0)
+                }
+            }
+
+            impl fmt::Display for TotalFloat<$t> {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    self.0.fmt(f)
+                }
+            }
+
+            impl Element for TotalFloat<$t> {
+                const NAME: &'static str = stringify!($t);
+
+                fn parse(token: &str, options: &ParseOptions) -> Result<Self, ParseError> {
+                    Self::parse_bytes(token.as_bytes(), options)
+                }
+
+                fn parse_bytes(token: &[u8], options: &ParseOptions) -> Result<Self, ParseError> {
+                    let value = fast_float2::parse::<$t, _>(token).map_err(|_| ParseError::Invalid)?;
+                    if !value.is_nan() {
+                        return Ok(TotalFloat(value));
+                    }
+                    match options.nan_policy {
+                        NanPolicy::First => Ok(TotalFloat(-<$t>::NAN)),
+                        NanPolicy::Last => Ok(TotalFloat(<$t>::NAN)),
+                        NanPolicy::Reject => Err(ParseError::Rejected("NaN values are rejected")),
+                    }
+                }
+            }
+
+            /// Writes the shortest digits that read back as the same value, formatted on the stack
+            /// without going through `fmt`
+            impl Format for TotalFloat<$t> {
+                fn format_to<W: Write>(&self, output: &mut W) -> io::Result<()> {
+                    output.write_all(ryu::Buffer::new().format(self.0).as_bytes())
+                }
+            }
+
+            impl Random for TotalFloat<$t> {
+                fn random_vec(count: usize) -> Vec<Self> {
+                    <$t>::random_vec(count).into_iter().map(TotalFloat).collect()
+                }
+            }
+        )*
+    };
+}
+
+impl_total_float!(f32, f64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(tokens: &[&str], nan_policy: NanPolicy) -> Vec<String> {
+        let options = ParseOptions { nan_policy, ..ParseOptions::default() };
+        let mut values: Vec<TotalFloat<f64>> = tokens
+            .iter()
+            .map(|token| TotalFloat::parse(token, &options).unwrap())
+            .collect();
+        values.sort();
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_total_order() {
+        let sorted = parse_all(&["1.5", "-0", "inf", "0", "-inf", "-2"], NanPolicy::Last);
+        assert_eq!(sorted, vec!["-inf", "-2", "-0", "0", "1.5", "inf"]);
+    }
+
+    #[test]
+    fn test_nan_policy() {
+        assert_eq!(parse_all(&["1", "NaN", "-inf"], NanPolicy::First), vec!["NaN", "-inf", "1"]);
+        assert_eq!(parse_all(&["1", "-NaN", "inf"], NanPolicy::Last), vec!["1", "inf", "NaN"]);
+
+        let options = ParseOptions { nan_policy: NanPolicy::Reject, ..ParseOptions::default() };
+        assert!(matches!(TotalFloat::<f32>::parse("NaN", &options), Err(ParseError::Rejected(_))));
+        assert_eq!(TotalFloat::<f32>::parse("x", &options), Err(ParseError::Invalid));
+    }
+
+    #[test]
+    fn test_format_round_trip() {
+        let options = ParseOptions::default();
+        for value in [0.0, -0.0, 1.0, -2.5, 0.1, 1e-7, 1e300, f64::MIN_POSITIVE, f64::MAX, f64::INFINITY, f64::NEG_INFINITY] {
+            let mut output = Vec::new();
+            TotalFloat(value).format_to(&mut output).unwrap();
+            assert_eq!(TotalFloat::<f64>::parse_bytes(&output, &options).unwrap().0.to_bits(), value.to_bits(), "{}", value);
+        }
+        let mut output = Vec::new();
+        TotalFloat(f32::NAN).format_to(&mut output).unwrap();
+        assert_eq!(output, b"NaN");
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        let tokens = [
+            "0", "-0", "+1.5", "1e10", "1E-7", ".5", "5.", "-inf", "+infinity", "Infinity", "nan", "-NaN",
+            "3.4028236e38", "1e400", "2.2250738585072011e-308", "0.1", "", "-", "e5", "1e", "1.5x", " 1", "0x10", "1_0",
+        ];
+        // the sign of NaN is set by the policy, so NaN values are only compared as NaN
+        let bits = |value: f64| if value.is_nan() { u64::MAX } else { value.to_bits() };
+        let options = ParseOptions::default();
+        for token in tokens {
+            let parsed = TotalFloat::<f64>::parse_bytes(token.as_bytes(), &options).ok().map(|value| bits(value.0));
+            assert_eq!(parsed, token.parse::<f64>().ok().map(bits), "{}", token);
+            let parsed = TotalFloat::<f32>::parse_bytes(token.as_bytes(), &options).ok().map(|value| bits(value.0 as f64));
+            assert_eq!(parsed, token.parse::<f32>().ok().map(|value| bits(value as f64)), "{}", token);
+        }
+    }
+}
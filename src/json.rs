@@ -0,0 +1,147 @@
+use crate::element::{Element, Format, ParseError, ParseOptions, Random};
+use crate::record::{Field, Key, Record};
+use rand::Rng;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+/// Line of JSON Lines input compared by the fields selected with path keys.
+/// The line is written back unchanged, lines that are not valid JSON are rejected.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct JsonRecord(Record);
+
+impl JsonRecord {
+    fn field<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+        path.iter().try_fold(value, |value, name| match value {
+            Value::Object(object) => object.get(name),
+            Value::Array(array) => array.get(name.parse::<usize>().ok()?),
+            _ => None,
+        })
+    }
+
+    fn scalar(value: Option<&Value>) -> Option<Cow<'_, str>> {
+        match value? {
+            Value::String(text) => Some(Cow::Borrowed(text)),
+            Value::Number(number) => Some(Cow::Owned(number.to_string())),
+            Value::Bool(flag) => Some(Cow::Owned(flag.to_string())),
+            Value::Null | Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for JsonRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Format for JsonRecord {
+    fn format_to<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        self.0.format_to(output)
+    }
+}
+
+impl Element for JsonRecord {
+    const NAME: &'static str = "json";
+    const WHOLE_LINE: bool = true;
+
+    fn check_options(options: &ParseOptions) -> Result<(), String> {
+        match options.keys.iter().find(|spec| matches!(spec.field, Field::Column(_))) {
+            Some(spec) => Err(format!("The key {} is a column, JSON lines are sorted by paths of fields like .user.id", spec.field)),
+            None => Ok(()),
+        }
+    }
+
+    fn parse(token: &str, options: &ParseOptions) -> Result<Self, ParseError> {
+        let json = serde_json::from_str::<Value>(token).map_err(|_| ParseError::Invalid)?;
+        let mut keys = Vec::with_capacity(options.keys.len());
+        for spec in &options.keys {
+            let field = match &spec.field {
+                Field::Path(path) => JsonRecord::scalar(JsonRecord::field(&json, path)),
+                Field::Column(_) => None,
+            };
+            keys.push(Key { value: Record::key_value(field.as_deref(), spec.key_type, options)?, direction: spec.direction });
+        }
+        Ok(JsonRecord(Record::new(token, keys)))
+    }
+
+    fn heap_size(&self) -> usize {
+        self.0.heap_size()
+    }
+}
+
+impl Random for JsonRecord {
+    fn random_vec(count: usize) -> Vec<Self> {
+        let mut rng = rand::thread_rng();
+        let names = String::random_vec(count);
+        names
+            .into_iter()
+            .map(|name| {
+                let line = serde_json::json!({
+                    "id": rng.gen::<u32>(),
+                    "user": { "name": name },
+                    "ts": rng.gen_range(0.0..1.0e9),
+                });
+                JsonRecord(Record::new(&line.to_string(), Vec::new()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::MissingPolicy;
+
+    fn sort_lines(lines: &[&str], keys: &[&str], missing_policy: MissingPolicy) -> Vec<String> {
+        let options = ParseOptions {
+            keys: keys.iter().map(|key| key.parse().unwrap()).collect(),
+            missing_policy,
+            ..ParseOptions::default()
+        };
+        let mut records: Vec<JsonRecord> = lines.iter().map(|line| JsonRecord::parse(line, &options).unwrap()).collect();
+        records.sort();
+        records.iter().map(|record| record.to_string()).collect()
+    }
+
+    #[test]
+    fn test_field_path() {
+        let lines = [
+            r#"{"user": {"id": 10}, "ts": 1}"#,
+            r#"{"user": {"id": 9}, "ts": 2}"#,
+            r#"{"user": {}, "ts": 3}"#,
+            r#"{"user": {"id": 10}, "ts": 0.5}"#,
+        ];
+        assert_eq!(
+            sort_lines(&lines, &[".user.id,int", ".ts,num,desc"], MissingPolicy::First),
+            vec![lines[2], lines[1], lines[0], lines[3]]
+        );
+    }
+
+    #[test]
+    fn test_array_index_and_invalid_line() {
+        let lines = [r#"{"tags": ["b", "a"]}"#, r#"{"tags": []}"#, r#"{"tags": ["a"]}"#];
+        assert_eq!(sort_lines(&lines, &[".tags.0"], MissingPolicy::Last), vec![lines[2], lines[0], lines[1]]);
+        assert!(matches!(JsonRecord::parse("not json", &ParseOptions::default()), Err(ParseError::Invalid)));
+    }
+
+    #[test]
+    fn test_missing_reject() {
+        let options = ParseOptions {
+            keys: vec![".ts,num".parse().unwrap()],
+            missing_policy: MissingPolicy::Reject,
+            ..ParseOptions::default()
+        };
+        assert!(JsonRecord::parse(r#"{"ts": 1}"#, &options).is_ok());
+        assert!(matches!(JsonRecord::parse(r#"{"id": 1}"#, &options), Err(ParseError::Rejected(_))));
+    }
+
+    #[test]
+    fn test_check_options() {
+        let options = |key: &str| ParseOptions { keys: vec![key.parse().unwrap()], ..ParseOptions::default() };
+        assert!(JsonRecord::check_options(&options(".user.id,int")).is_ok());
+        assert!(JsonRecord::check_options(&options("1")).is_err());
+    }
+}
@@ -0,0 +1,23 @@
+pub mod block_io;
+pub mod cancel;
+pub mod checker;
+pub mod disk;
+pub mod element;
+pub mod encoding;
+pub mod error;
+pub mod file_reader;
+pub mod float;
+pub mod json;
+pub mod memory;
+pub mod merge_sorter;
+pub mod progress;
+pub mod record;
+pub mod sorter;
+pub mod stats;
+pub mod thread_pool;
+
+pub use cancel::CancelToken;
+pub use error::SortError;
+pub use merge_sorter::file::ExecPolicy;
+pub use progress::Progress;
+pub use sorter::ExternalSorter;
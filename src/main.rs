@@ -1,12 +1,23 @@
-mod file_reader;
-mod merge_sorter;
-mod thread_pool;
-mod checker;
-
 use argh::FromArgs;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
 use std::thread::available_parallelism;
-use std::time::Instant;
-use crate::merge_sorter::file::ExecPolicy;
+use std::time::{Duration, Instant};
+use large_data_sorter::{cancel, checker, dispatch_element_type, file_reader, memory, progress, CancelToken, ExternalSorter, Progress};
+use large_data_sorter::block_io::IoOptions;
+use large_data_sorter::element::{Element, ElementType, Format, ParseOptions, Random};
+use large_data_sorter::error::SortError;
+use large_data_sorter::file_reader::STDIO_PATH;
+use large_data_sorter::float::NanPolicy;
+use large_data_sorter::memory::SystemMemory;
+use large_data_sorter::record::{KeySpec, MissingPolicy};
+use large_data_sorter::merge_sorter::file::{ExecPolicy, Sort};
+use large_data_sorter::progress::{format_duration, format_size, ProgressSnapshot};
+use large_data_sorter::stats::{ProcessStats, SortStats};
+use large_data_sorter::thread_pool::Channel;
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Configuration
@@ -25,101 +36,330 @@ enum Mode {
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "generator")]
-/// generates a file with random u32 numbers
+/// generates a file with random values of the given type
 struct Generator {
-    /// output path for generator
+    /// output path for generator, - for the standard output
     #[argh(option, short = 'o')]
     output_path: String,
 
-    /// u32 numbers count to be generated
+    /// values count to be generated
     #[argh(option, short = 'n')]
     numbers_count: usize,
+
+    /// type of generated values: u8, u16, u32, u64, i8, i16, i32, i64, f32, f64, string, record, json
+    #[argh(option, long = "type", default = "ElementType::U32")]
+    element_type: ElementType,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "sorter")]
 /// sorts a file using merge-sort algorithm
 struct Sorter {
-    /// path for input data to sort
+    /// path for input data to sort, - for the standard input
     #[argh(option, short = 'i')]
     input_path: String,
 
-    /// path for sorted output
+    /// path for sorted output, - for the standard output. The input when sorting in place
     #[argh(option, short = 'o')]
-    output_path: String,
+    output_path: Option<String>,
+
+    /// replace the input with the sorted values once all of them are sorted,
+    /// the output can't be the input otherwise
+    #[argh(switch)]
+    in_place: bool,
 
     /// maximum threads count to be used during sorting
     #[argh(option, short = 't', default = "available_threads()")]
     threads_count: usize,
 
-    /// maximum size of the file that can be sorted in ram
-    #[argh(option, short = 's', default = "default_ram()")]
+    /// maximum heap bytes taken at once by all threads for the values sorted in ram and the merge buffers,
+    /// estimated from a sample of the input. Sizes like 4G or 512M are accepted.
+    /// Half of the memory available to the process and its cgroup by default
+    #[argh(option, short = 's', default = "default_ram()", from_str_fn(memory::parse_size))]
     max_size: usize,
 
     /// available values:                                          |
     /// FullPar - sorting both files and in ram is parallel        |
     /// FilePar - only sorting a file is parallel                  |
     /// RamPar - only sorting in ram is parallel                   |
+    /// Pipelined - reading, parsing, sorting and writing overlap  |
     #[argh(option, short = 'e', default = "ExecPolicy::FullPar")]
     exec_policy: ExecPolicy,
+
+    /// type of sorted values: u8, u16, u32, u64, i8, i16, i32, i64, f32, f64, string, record, json
+    #[argh(option, long = "type", default = "ElementType::U32")]
+    element_type: ElementType,
+
+    /// placement of NaN values for f32 and f64: first, last or reject
+    #[argh(option, default = "NanPolicy::Last")]
+    nan: NanPolicy,
+
+    /// sort key of records given as field[,str|int|num][,asc|desc], can be repeated.
+    /// The field is a column number for record and a path like .user.id for json
+    #[argh(option, short = 'k')]
+    key: Vec<KeySpec>,
+
+    /// character separating fields of records, whitespace by default
+    #[argh(option, short = 'd')]
+    field_separator: Option<char>,
+
+    /// placement of records with missing keys: first, last or reject
+    #[argh(option, default = "MissingPolicy::Last")]
+    missing: MissingPolicy,
+
+    /// size in bytes of the blocks read ahead and written behind while merging files
+    #[argh(option, default = "IoOptions::default().block_size")]
+    block_size: usize,
+
+    /// bypass the page cache with O_DIRECT while merging files (Linux only)
+    #[argh(switch)]
+    direct_io: bool,
+
+    /// advise the kernel to read the merged files sequentially and drop the pages already read from the page cache while merging (Linux only)
+    #[argh(switch)]
+    fadvise: bool,
+
+    /// reserve the disk space of merge outputs before writing them while merging (Linux only)
+    #[argh(switch)]
+    preallocate: bool,
+
+    /// directory in which the temporary files are written, the current one by default
+    #[argh(option, default = "String::from(\".\")")]
+    temp_dir: String,
+
+    /// maximum bytes of temporary files kept at once, like 20G. The free space of the temporary
+    /// and output directories is checked before sorting in any case
+    #[argh(option, from_str_fn(memory::parse_size))]
+    max_temp_bytes: Option<usize>,
+
+    /// free the disk blocks of the input once its values are in sorted runs and of the temporary files
+    /// as they are merged, so the disk holds about 1.1 times the input instead of three times
+    /// (Linux only). The input is consumed. Taken anyway when the input wouldn't fit otherwise
+    #[argh(switch)]
+    low_disk: bool,
+
+    /// don't report the progress, which is otherwise a bar on a terminal and a line every 10 seconds elsewhere.
+    /// SIGUSR1 prints the progress of every phase in any case
+    #[argh(switch)]
+    no_progress: bool,
+
+    /// path of a JSON report written once the sort succeeded: sizes, counts of values, runs and merges,
+    /// bytes and time of every phase, process I/O, peak memory and thread utilisation
+    #[argh(option)]
+    stats: Option<String>,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "checker")]
 /// checks if the given file is sorted
 struct Checker {
-    /// path of file to be checked
+    /// path of file to be checked, - for the standard input
     #[argh(option, short = 'i')]
     input_path: String,
+
+    /// type of checked values: u8, u16, u32, u64, i8, i16, i32, i64, f32, f64, string, record, json
+    #[argh(option, long = "type", default = "ElementType::U32")]
+    element_type: ElementType,
+
+    /// placement of NaN values for f32 and f64: first, last or reject
+    #[argh(option, default = "NanPolicy::Last")]
+    nan: NanPolicy,
+
+    /// sort key of records given as field[,str|int|num][,asc|desc], can be repeated.
+    /// The field is a column number for record and a path like .user.id for json
+    #[argh(option, short = 'k')]
+    key: Vec<KeySpec>,
+
+    /// character separating fields of records, whitespace by default
+    #[argh(option, short = 'd')]
+    field_separator: Option<char>,
+
+    /// placement of records with missing keys: first, last or reject
+    #[argh(option, default = "MissingPolicy::Last")]
+    missing: MissingPolicy,
 }
 
 fn available_threads() -> usize {
     match available_parallelism() {
         Ok(number) => number.get(),
         Err(e) => {
-            println!("Error: {}. Using only 1 thread", e);
+            eprintln!("Error: {}. Using only 1 thread", e);
             1
         }
     }
 }
 
 fn default_ram() -> usize {
-    (sys_info::mem_info().expect("Failed to get ram size. Need to specify it manually").total * 1000 / 4) as usize
+    match memory::default_budget(&SystemMemory::default()) {
+        Some(size) => size,
+        None => {
+            eprintln!("Error: failed to get the available memory. Using {} bytes", memory::FALLBACK_BUDGET);
+            memory::FALLBACK_BUDGET
+        }
+    }
 }
 
-fn dispatch_task(config: Config) {
+/// Prints a message, on the standard error when the values are written to the standard output
+fn report(output_path: &str, message: std::fmt::Arguments) {
+    if output_path == STDIO_PATH {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn generate<T: Random + Format>(generator: Generator) -> Result<(), SortError> {
+    let now = Instant::now();
+    if generator.output_path == STDIO_PATH {
+        file_reader::write_random_to::<T, _>(io::stdout().lock(), "<standard output>", generator.numbers_count)?;
+    } else {
+        file_reader::write_random_data::<T>(&generator.output_path, generator.numbers_count)?;
+    }
+    report(&generator.output_path, format_args!("File has been generated in {} ms", now.elapsed().as_millis()));
+    Ok(())
+}
+
+fn sort<T: Sort + Channel>(sorter: Sorter) -> Result<(), SortError> {
+    let now = Instant::now();
+    let output_path = sorter.output_path.unwrap_or_else(|| sorter.input_path.clone());
+    // the sort removes its temporary files when interrupted, where signals can be handled
+    let cancel_token = CancelToken::on_signals().unwrap_or_default();
+    let options = ParseOptions {
+        nan_policy: sorter.nan,
+        keys: sorter.key,
+        field_separator: sorter.field_separator,
+        missing_policy: sorter.missing,
+    };
+    let io_options = IoOptions {
+        block_size: sorter.block_size,
+        direct_io: sorter.direct_io,
+        fadvise: sorter.fadvise,
+        preallocate: sorter.preallocate,
+    };
+    let progress = if sorter.no_progress { Progress::new() } else { progress_reporter() };
+    let process = ProcessStats::default();
+    let io_before = process.io();
+    let sorting = ExternalSorter::new()
+        .memory(sorter.max_size)
+        .threads(sorter.threads_count)
+        .policy(sorter.exec_policy)
+        .temp_dir(&sorter.temp_dir)
+        .in_place(sorter.in_place)
+        .max_temp_bytes(sorter.max_temp_bytes.map_or(u64::MAX, |bytes| bytes as u64))
+        .low_disk(sorter.low_disk)
+        .cancel_token(cancel_token)
+        .progress(progress.clone())
+        .parse_options(options)
+        .io_options(io_options);
+
+    let (done_sender, done) = mpsc::channel::<()>();
+    thread::scope(|scope| {
+        if progress::handle_snapshot_signal().is_ok() {
+            scope.spawn(|| print_requested_snapshots(&progress, done));
+        }
+        let result = sorting.sort_file::<T>(&sorter.input_path, &output_path);
+        drop(done_sender);
+        result
+    })?;
+    if let Some(stats_path) = &sorter.stats {
+        let io = process.io().zip(io_before).map(|(after, before)| after.since(&before));
+        let stats = SortStats::new(&progress.snapshot(), io, process.peak_rss());
+        fs::write(stats_path, stats.to_json() + "\n").map_err(SortError::io(stats_path))?;
+    }
+    report(&output_path, format_args!("File has been sorted in {} ms", now.elapsed().as_millis()));
+    Ok(())
+}
+
+/// Interval of the progress lines when the standard error isn't a terminal
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval of the redrawing of the progress bar on a terminal
+const PROGRESS_BAR_INTERVAL: Duration = Duration::from_millis(200);
+const PROGRESS_BAR_WIDTH: usize = 30;
+/// Interval of the checks of SIGUSR1
+const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reports the progress to the standard error, redrawing a bar on a terminal and printing lines elsewhere
+fn progress_reporter() -> Progress {
+    let terminal = io::stderr().is_terminal();
+    let interval = if terminal { PROGRESS_BAR_INTERVAL } else { PROGRESS_LOG_INTERVAL };
+    Progress::with_callback(interval, move |snapshot| {
+        if !terminal {
+            eprintln!("Progress: {}", snapshot);
+            return;
+        }
+        let bar = match snapshot.fraction() {
+            Some(fraction) => {
+                let filled = (fraction * PROGRESS_BAR_WIDTH as f64) as usize;
+                format!("[{}{}] ", "#".repeat(filled), ".".repeat(PROGRESS_BAR_WIDTH - filled))
+            }
+            None => String::new(),
+        };
+        eprint!("\r{}{}\x1b[K", bar, snapshot);
+        if snapshot.finished {
+            eprintln!();
+        }
+    })
+}
+
+/// Prints the progress of every phase when SIGUSR1 is received, until the sort is done
+fn print_requested_snapshots(progress: &Progress, done: mpsc::Receiver<()>) {
+    while let Err(mpsc::RecvTimeoutError::Timeout) = done.recv_timeout(SNAPSHOT_CHECK_INTERVAL) {
+        if progress::snapshot_requested() {
+            print_snapshot(&progress.snapshot());
+        }
+    }
+}
+
+fn print_snapshot(snapshot: &ProgressSnapshot) {
+    eprintln!();
+    eprintln!("Status: {}", snapshot);
+    for phase in &snapshot.phases {
+        eprintln!("  {:<16}{:>10} {:>14} values", phase.phase.to_string(), format_size(phase.bytes), phase.values);
+    }
+    eprintln!("  last progress {} ago", format_duration(snapshot.idle));
+}
+
+fn check<T: Element + Ord>(checker: Checker) -> Result<(), SortError> {
+    let options = ParseOptions {
+        nan_policy: checker.nan,
+        keys: checker.key,
+        field_separator: checker.field_separator,
+        missing_policy: checker.missing,
+    };
+    T::check_options(&options).map_err(SortError::InvalidOptions)?;
+    if checker::is_sorted::<T>(&checker.input_path, &options)? {
+        println!("File {} is sorted", checker.input_path);
+    } else {
+        println!("File {} is not sorted", checker.input_path);
+    }
+    Ok(())
+}
+
+fn dispatch_task(config: Config) -> Result<(), SortError> {
     match config.mode {
         Mode::Generator(generator) => {
-            let now = Instant::now();
-            let result = file_reader::write_random_data(&generator.output_path, generator.numbers_count);
-            println!("File has been generated in {} ms", now.elapsed().as_millis());
-            match result {
-                Err(err) => println!("{}", err),
-                _ => {}
-            };
+            dispatch_element_type!(generator.element_type, generate(generator))
         }
         Mode::Sorter(sorter) => {
-            let now = Instant::now();
-            merge_sorter::file::merge_sort::<u32>(&sorter.input_path, &sorter.output_path, sorter.max_size, sorter.threads_count, sorter.exec_policy);
-            println!("File has been sorted in {} ms", now.elapsed().as_millis());
+            dispatch_element_type!(sorter.element_type, sort(sorter))
         }
         Mode::Checker(checker) => {
-            match checker::is_sorted::<u32>(&checker.input_path) {
-                Some(result) => {
-                    if result {
-                        println!("File {} is sorted", checker.input_path);
-                    } else {
-                        println!("File {} is not sorted", checker.input_path);
-                    }
-                }
-                None => {
-                    println!("Error occured while reading {}", checker.input_path);
-                }
-            };
+            dispatch_element_type!(checker.element_type, check(checker))
         }
     }
 }
 
+/// Exit status of a sort cancelled by a signal without the signal number, as for SIGINT
+const CANCELLED_STATUS: i32 = 130;
+
 fn main() {
-    dispatch_task(argh::from_env());
+    if let Err(err) = dispatch_task(argh::from_env()) {
+        eprintln!("Error: {}", err);
+        // like the shells do for commands stopped by a signal
+        let status = match err {
+            SortError::Cancelled => cancel::received_signal().map_or(CANCELLED_STATUS, |signal| 128 + signal),
+            _ => 1,
+        };
+        process::exit(status);
+    }
 }
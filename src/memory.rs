@@ -0,0 +1,367 @@
+use crate::file_reader::FORMAT_CHUNK_LENGTH;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+/// Copies of the values alive at once while they are sorted in RAM on a single thread:
+/// the parsed values, both sorted halves and their merge
+const SEQ_SORT_COPIES: f64 = 3.0;
+/// The parallel sort also copies the left half of every slice it hands to another worker
+const PAR_SORT_COPIES: f64 = 4.0;
+
+/// Bytes of input decoded to estimate the heap bytes taken by its values
+pub const SAMPLE_SIZE: u64 = 1 << 16;
+
+/// Values decoded from a sample of the input
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Sample {
+    /// Encoded size of the values
+    pub bytes: usize,
+    pub values: usize,
+    /// Heap bytes taken by the values and the `Vec` holding them
+    pub heap: usize,
+}
+
+/// Estimate of the peak heap bytes taken by sorting a part of the input in RAM,
+/// extrapolated from a sample of the input. Memory mapped input files are not on the heap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryModel {
+    heap_per_byte: f64,
+    values_per_byte: f64,
+}
+
+impl MemoryModel {
+    pub fn new(sample: Sample) -> MemoryModel {
+        if sample.bytes == 0 {
+            return MemoryModel { heap_per_byte: 0.0, values_per_byte: 0.0 };
+        }
+        MemoryModel {
+            heap_per_byte: sample.heap as f64 / sample.bytes as f64,
+            values_per_byte: sample.values as f64 / sample.bytes as f64,
+        }
+    }
+
+    /// Peak heap bytes of loading, sorting and storing `size` bytes of input,
+    /// on a single thread or on the `threads` workers of a pool
+    pub fn in_ram_size(&self, size: u64, threads: usize) -> u64 {
+        let size = size as f64;
+        let values = size * self.heap_per_byte;
+        let (copies, formatted_chunks) = match threads {
+            0 | 1 => (SEQ_SORT_COPIES, 1),
+            // the pending chunks and the one written
+            _ => (PAR_SORT_COPIES, 2 * threads + 1),
+        };
+        // a value takes as many bytes formatted as in the input, and a growing formatting buffer
+        // briefly holds both its old and new allocations, up to three times the formatted chunk
+        let bytes_per_value = if self.values_per_byte > 0.0 { 1.0 / self.values_per_byte } else { 0.0 };
+        let formatted = 3.0 * f64::min(size, (formatted_chunks * FORMAT_CHUNK_LENGTH) as f64 * bytes_per_value);
+
+        f64::max(values * copies, values + formatted).ceil() as u64
+    }
+
+    /// Largest chunk of input such that `chunks` of them and `copies` of their values take at most `budget` heap bytes
+    pub fn chunk_size(&self, budget: usize, chunks: usize, copies: usize) -> usize {
+        (budget as f64 / (chunks as f64 + self.heap_per_byte * copies as f64)) as usize
+    }
+}
+
+/// Heap bytes shared by all the threads of a sort. Reserving more bytes than are available
+/// waits until other reservations are released, so parallel branches can't exceed the budget together.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    total: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    pub fn new(total: usize) -> MemoryBudget {
+        MemoryBudget { total, available: Mutex::new(total), released: Condvar::new() }
+    }
+
+    /// Waits until the bytes are available and holds them until the reservation is dropped.
+    /// A reservation larger than the whole budget takes all of it.
+    pub fn reserve(&self, bytes: usize) -> Reservation<'_> {
+        let bytes = usize::min(bytes, self.total);
+        let available = self.available.lock().unwrap();
+        let mut available = self.released.wait_while(available, |available| *available < bytes).unwrap();
+        *available -= bytes;
+        Reservation { budget: self, bytes }
+    }
+}
+
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+/// Parses a size in bytes with an optional binary unit, e.g. `4G`, `512MiB`, `1.5g` or `1000`
+pub fn parse_size(input: &str) -> Result<usize, String> {
+    let input = input.trim();
+    let split = input.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let shift = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        "t" | "tb" | "tib" => 40,
+        _ => return Err(format!("Unknown size unit: {}", unit)),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(number) if number >= 0.0 && number.is_finite() => Ok((number * (1_u64 << shift) as f64) as usize),
+        _ => Err(format!("Wrong size: {}", input)),
+    }
+}
+
+/// Files describing the memory of the system.
+/// The `/proc` and cgroup trees are given so that tests can use fake ones.
+#[derive(Debug, Clone)]
+pub struct SystemMemory {
+    proc_dir: PathBuf,
+    cgroup_dir: PathBuf,
+}
+
+impl Default for SystemMemory {
+    fn default() -> Self {
+        SystemMemory::new("/proc", "/sys/fs/cgroup")
+    }
+}
+
+impl SystemMemory {
+    pub fn new(proc_dir: impl Into<PathBuf>, cgroup_dir: impl Into<PathBuf>) -> SystemMemory {
+        SystemMemory { proc_dir: proc_dir.into(), cgroup_dir: cgroup_dir.into() }
+    }
+
+    /// Bytes that can be allocated without swapping or reaching the limit of a cgroup of the process
+    pub fn available(&self) -> Option<u64> {
+        match (self.mem_available(), self.cgroup_available()) {
+            (Some(system), Some(cgroup)) => Some(u64::min(system, cgroup)),
+            (system, cgroup) => system.or(cgroup),
+        }
+    }
+
+    fn mem_available(&self) -> Option<u64> {
+        proc_size(&self.proc_dir.join("meminfo"), "MemAvailable")
+    }
+
+    /// Memory left under the lowest limit of the cgroups of the process and their ancestors.
+    /// Inactive page cache counts as available, as it is reclaimed before the limit is reached.
+    fn cgroup_available(&self) -> Option<u64> {
+        let cgroups = fs::read_to_string(self.proc_dir.join("self").join("cgroup")).ok()?;
+        let mut available = None;
+        for line in cgroups.lines() {
+            let mut fields = line.splitn(3, ':');
+            let (hierarchy, controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(hierarchy), Some(controllers), Some(path)) => (hierarchy, controllers, path),
+                _ => continue,
+            };
+            let files = if hierarchy == "0" && controllers.is_empty() {
+                CgroupFiles { dir: self.cgroup_dir.clone(), limit: "memory.max", usage: "memory.current", inactive: "inactive_file" }
+            } else if controllers.split(',').any(|controller| controller == "memory") {
+                CgroupFiles { dir: self.cgroup_dir.join("memory"), limit: "memory.limit_in_bytes", usage: "memory.usage_in_bytes", inactive: "total_inactive_file" }
+            } else {
+                continue;
+            };
+
+            // the path may not be visible in the mounted tree from inside a cgroup namespace
+            let mut dir = Some(files.dir.join(path.trim_start_matches('/')));
+            while let Some(current) = dir {
+                if let Some(left) = files.available(&current) {
+                    available = Some(available.map_or(left, |available: u64| available.min(left)));
+                }
+                dir = current.parent().filter(|parent| parent.starts_with(&files.dir)).map(Path::to_path_buf);
+            }
+        }
+        available
+    }
+}
+
+/// Bytes of the `key: N kB` line of a `/proc` file like `meminfo` or `status`
+pub(crate) fn proc_size(path: &Path, key: &str) -> Option<u64> {
+    let contents = fs::read_to_string(path).ok()?;
+    let value = contents.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))?;
+    let kilobytes = value.trim().trim_end_matches("kB").trim().parse::<u64>().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Memory files of a cgroup hierarchy, v2 when mounted at the root and v1 under `memory`
+struct CgroupFiles {
+    dir: PathBuf,
+    limit: &'static str,
+    usage: &'static str,
+    inactive: &'static str,
+}
+
+impl CgroupFiles {
+    fn available(&self, cgroup: &Path) -> Option<u64> {
+        // "max" means no limit in v2, v1 gives a huge number instead
+        let limit = fs::read_to_string(cgroup.join(self.limit)).ok()?.trim().parse::<u64>().ok()?;
+        let usage = fs::read_to_string(cgroup.join(self.usage)).ok().and_then(|usage| usage.trim().parse::<u64>().ok()).unwrap_or(0);
+        let inactive = fs::read_to_string(cgroup.join("memory.stat")).ok().and_then(|stat| {
+            stat.lines()
+                .find_map(|line| line.strip_prefix(self.inactive).and_then(|value| value.strip_prefix(' ')))
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        });
+        Some(limit.saturating_sub(usage.saturating_sub(inactive.unwrap_or(0))))
+    }
+}
+
+/// Budget used when the memory available to the process can't be read
+pub const FALLBACK_BUDGET: usize = 1 << 30;
+
+/// Default budget: half of the memory available to the process,
+/// leaving room for the page cache of the files being sorted and for other processes
+pub fn default_budget(memory: &SystemMemory) -> Option<usize> {
+    let available = memory.available().or_else(|| sys_info::mem_info().ok().map(|info| info.avail * 1024))?;
+    Some(usize::try_from(available / 2).unwrap_or(usize::MAX))
+}
+
+#[cfg(test)]
+pub(crate) mod counting_allocator {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// Counts the heap bytes allocated by the threads that measure them
+    struct CountingAllocator;
+
+    thread_local! {
+        static MEASURING: Cell<bool> = const { Cell::new(false) };
+        static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+        static PEAK: Cell<isize> = const { Cell::new(0) };
+    }
+
+    fn count(change: isize) {
+        if MEASURING.try_with(Cell::get).unwrap_or(false) {
+            let allocated = ALLOCATED.get() + change;
+            ALLOCATED.set(allocated);
+            PEAK.set(isize::max(PEAK.get(), allocated));
+        }
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout.size() as isize);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            count(-(layout.size() as isize));
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            // the old and the new blocks are both allocated while the data is moved
+            count(new_size as isize);
+            count(-(layout.size() as isize));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Peak heap bytes allocated by the current thread while running the function,
+    /// above what was allocated before
+    pub(crate) fn peak_heap<R>(func: impl FnOnce() -> R) -> (R, usize) {
+        ALLOCATED.set(0);
+        PEAK.set(0);
+        MEASURING.set(true);
+        let result = func();
+        MEASURING.set(false);
+        (result, PEAK.get() as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_ram_size() {
+        let model = MemoryModel::new(Sample { bytes: 1000, values: 100, heap: 400 });
+        assert_eq!(model.in_ram_size(0, 1), 0);
+        // the sorting copies outweigh a single formatting buffer
+        assert_eq!(model.in_ram_size(10_000_000, 1), 12_000_000);
+        assert_eq!(model.in_ram_size(100_000_000, 4), 160_000_000);
+        // small inputs are formatted at once
+        assert_eq!(model.in_ram_size(1000, 64), 3400);
+        assert_eq!(model.chunk_size(30_000, 1, 5), 10_000);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = std::sync::Arc::new(MemoryBudget::new(100));
+        let first = budget.reserve(60);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let budget_copy = std::sync::Arc::clone(&budget);
+        let waiting = std::thread::spawn(move || {
+            let _second = budget_copy.reserve(60);
+            sender.send(()).unwrap();
+        });
+
+        assert!(receiver.recv_timeout(std::time::Duration::from_millis(50)).is_err());
+        drop(first);
+        receiver.recv().unwrap();
+        waiting.join().unwrap();
+        drop(budget.reserve(1000));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert_eq!(parse_size("4G"), Ok(4 << 30));
+        assert_eq!(parse_size("512MiB"), Ok(512 << 20));
+        assert_eq!(parse_size("1.5k"), Ok(1536));
+        assert!(parse_size("4X").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1").is_err());
+    }
+
+    fn write_files(root: &Path, files: &[(&str, &str)]) {
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_available_memory() {
+        let root = std::env::temp_dir().join(format!("system_memory_{}", std::process::id()));
+        let meminfo = "MemTotal:       16000000 kB\nMemAvailable:    8000000 kB\n";
+        write_files(&root, &[
+            ("v2/proc/meminfo", meminfo),
+            ("v2/proc/self/cgroup", "0::/kubepods/job\n"),
+            ("v2/cgroup/memory.max", "max\n"),
+            ("v2/cgroup/kubepods/memory.max", "3000000000\n"),
+            ("v2/cgroup/kubepods/memory.current", "1000000000\n"),
+            ("v2/cgroup/kubepods/job/memory.max", "max\n"),
+            ("v1/proc/meminfo", meminfo),
+            ("v1/proc/self/cgroup", "5:cpu:/\n4:memory:/\n0::/\n"),
+            ("v1/cgroup/memory/memory.limit_in_bytes", "2000000000\n"),
+            ("v1/cgroup/memory/memory.usage_in_bytes", "1500000000\n"),
+            ("v1/cgroup/memory/memory.stat", "cache 10\ntotal_inactive_file 500000000\n"),
+            ("host/proc/meminfo", meminfo),
+            ("host/proc/self/cgroup", "0::/\n"),
+            ("host/cgroup/memory.max", "max\n"),
+        ]);
+
+        let available = |tree: &str| SystemMemory::new(root.join(tree).join("proc"), root.join(tree).join("cgroup")).available();
+        // the limit of an ancestor applies to the cgroup of the process
+        assert_eq!(available("v2"), Some(2_000_000_000));
+        assert_eq!(available("v1"), Some(1_000_000_000));
+        assert_eq!(available("host"), Some(8_000_000 * 1024));
+        assert_eq!(available("missing"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,449 +1,1568 @@
-pub mod ram {
-    use crate::thread_pool::{Channel, ThreadPool};
-    use std::sync::{Arc, Mutex};
-
-    pub trait Sort: Clone + PartialOrd + std::fmt::Debug + Ord {}
-
-    impl<T: Clone + PartialOrd + std::fmt::Debug + Ord> Sort for T {}
-
-    pub fn merge_sort<T: Sort + Channel>(slice: &[T], threads_count: usize) -> Vec<T> {
-        match threads_count {
-            0 | 1 => merge_sort_seq(slice),
-            _ => merge_sort_par(slice, threads_count),
-        }
-    }
-
-    pub fn merge_sort_seq<T: Sort>(slice: &[T]) -> Vec<T> {
-        match slice.len() {
-            0..=1 => slice.to_vec(),
-            2 => {
-                if slice[0] > slice[1] {
-                    return vec![slice[1].clone(), slice[0].clone()];
-                }
-                slice.to_vec()
-            }
-            3..=100 => {
-                let mut result = slice.to_vec();
-                result.sort();
-                result
-            }
-            _ => {
-                let middle = slice.len() / 2;
-                let left_sorted = merge_sort_seq(&slice[0..middle]);
-                let right_sorted = merge_sort_seq(&slice[middle..]);
-                merge(&left_sorted, &right_sorted)
-            }
-        }
-    }
-
-    pub fn merge_sort_par<T: Sort + Channel>(slice: &[T], threads_count: usize) -> Vec<T> {
-        merge_sort_par_helper_from_pool(&slice, Arc::new(Mutex::new(ThreadPool::new(threads_count))))
-    }
-
-    pub fn merge_sort_par_helper_from_pool<T: Sort + Channel>(slice: &[T], pool: Arc<Mutex<ThreadPool<()>>>) -> Vec<T> {
-        if pool.lock().unwrap().is_available() {
-            merge_sort_par_helper_unchecked(slice, pool)
-        } else {
-            merge_sort_seq(slice)
-        }
-    }
-
-    fn merge_sort_par_helper_unchecked<T: Sort + Channel>(slice: &[T], pool: Arc<Mutex<ThreadPool<()>>>) -> Vec<T> {
-        match slice.len() {
-            0..=1 => slice.to_vec(),
-            2 => {
-                if slice[0] > slice[1] {
-                    return vec![slice[1].clone(), slice[0].clone()];
-                }
-                slice.to_vec()
-            }
-            _ => {
-                let middle = slice.len() / 2;
-
-                let left = slice[0..middle].to_vec();
-                let new_pool = Arc::clone(&pool);
-                let left_sorted = Arc::new(Mutex::new(Vec::<T>::new()));
-                let left_sorted_copy = Arc::clone(&left_sorted);
-                let result = pool.lock().unwrap().execute(move || *left_sorted_copy.lock().unwrap() = merge_sort_par_helper_from_pool(&left, new_pool));
-                let right_sorted = merge_sort_par_helper_from_pool(&slice[middle..], Arc::clone(&pool));
-                result.recv().unwrap();
-                let left_sorted = Arc::try_unwrap(left_sorted).unwrap().into_inner().unwrap();
-
-                merge(&left_sorted, &right_sorted)
-            }
-        }
-    }
-
-    fn merge<T: Sort>(left: &[T], right: &[T]) -> Vec<T> {
-        let mut left_pos = 0;
-        let mut right_pos = 0;
-        let mut merged: Vec<T> = Vec::with_capacity(left.len() + right.len());
-
-        while left_pos != left.len() && right_pos < right.len() {
-            if left[left_pos] < right[right_pos] {
-                merged.push(left[left_pos].clone());
-                left_pos += 1;
-            } else {
-                merged.push(right[right_pos].clone());
-                right_pos += 1;
-            }
-        }
-
-        if left_pos < left.len() {
-            merged.extend_from_slice(&left[left_pos..]);
-        }
-
-        if right_pos < right.len() {
-            merged.extend_from_slice(&right[right_pos..]);
-        }
-
-        return merged;
-    }
-
-    #[cfg(test)]
-    mod tests {
-        use std::thread::available_parallelism;
-        use super::*;
-
-        #[test]
-        fn test_merge() {
-            let merged = merge([1, 4, 8, 10].as_slice(), [2, 3, 8, 9].as_slice());
-            assert_eq!(merged, vec![1, 2, 3, 4, 8, 8, 9, 10]);
-        }
-
-        #[test]
-        fn test_merge_sort_seq() {
-            let unsorted = vec![5, 1, 9, 10, 3, 45, 2, 4, 4, 12];
-            let mut sorted = unsorted.clone();
-            sorted.sort();
-            assert_eq!(merge_sort(&unsorted, 1), sorted)
-        }
-
-        #[test]
-        fn test_merge_sort_par() {
-            let unsorted = vec![5, 1, 9, 10, 3, 45, 2, 4, 4, 12];
-            let mut sorted = unsorted.clone();
-            sorted.sort();
-            assert_eq!(merge_sort(&unsorted, available_parallelism().unwrap().get()), sorted)
-        }
-    }
-}
-
-pub mod file {
-    use std::fs;
-    use std::fs::File;
-    use std::io::{BufRead, BufReader, BufWriter, Write};
-    use std::path::MAIN_SEPARATOR_STR;
-    use std::str::FromStr;
-    use std::sync::{Arc, Mutex};
-    use crate::file_reader;
-    use crate::file_reader::get_lines_count;
-
-    use crate::merge_sorter::ram;
-    use crate::thread_pool::{Channel, ThreadPool};
-
-    pub trait Sort: ram::Sort + FromStr + ToString + std::fmt::Debug {}
-
-    impl<T: ram::Sort + FromStr + ToString + std::fmt::Debug> Sort for T {}
-
-    #[derive(Debug, PartialEq)]
-    pub enum ExecPolicy {
-        FullPar,
-        FilePar,
-        RamPar,
-    }
-
-    impl FromStr for ExecPolicy {
-        type Err = &'static str;
-
-        fn from_str(input: &str) -> Result<ExecPolicy, Self::Err> {
-            match input {
-                "FullPar" => Ok(ExecPolicy::FullPar),
-                "FilePar" => Ok(ExecPolicy::FilePar),
-                "RamPar" => Ok(ExecPolicy::RamPar),
-                _ => Err("Wrong value"),
-            }
-        }
-    }
-
-    #[derive(Debug)]
-    struct FileData {
-        file: File,
-        path: String,
-        lines_count: usize,
-    }
-
-    pub fn merge_sort<T: Sort + Channel>(input: &str, output: &str, max_size_in_ram: usize, threads_count: usize, exec_policy: ExecPolicy) {
-        match threads_count {
-            0 | 1 => merge_sort_seq::<T>(input, output, max_size_in_ram),
-            _ => merge_sort_par::<T>(input, output, max_size_in_ram, threads_count, exec_policy)
-        }
-    }
-
-    pub fn merge_sort_seq<T: Sort + Channel>(input: &str, output_path: &str, max_size_in_ram: usize) {
-        let (dir_name, prepared_input) = prepare_input(&input);
-        let result = merge_sort_seq_helper::<T>(prepared_input, max_size_in_ram);
-
-        clean(&result.path, &output_path, &dir_name);
-    }
-
-    fn merge_sort_seq_helper<T: Sort + Channel>(input: FileData, max_size_in_ram: usize) -> FileData {
-        if input.file.metadata().unwrap().len() < max_size_in_ram as u64 {
-            return compute_in_ram_seq::<T>(&input.path);
-        }
-
-        let tmp_output_path = String::from(&input.path) + "m";
-
-        match split_file(input) {
-            Ok(files) => {
-                let left_sorted = merge_sort_seq_helper::<T>(files.0, max_size_in_ram);
-                let right_sorted = merge_sort_seq_helper::<T>(files.1, max_size_in_ram);
-
-                let tmp_output = File::create(&tmp_output_path).expect(format!("Couldn't open the file: {}", &tmp_output_path).as_str());
-                merge::<T>(left_sorted, right_sorted, FileData { file: tmp_output, path: tmp_output_path, lines_count: 0 })
-            }
-            Err(unit_file) => unit_file
-        }
-    }
-
-    pub fn merge_sort_par<T: Sort + Channel>(input: &str, output: &str, max_size_in_ram: usize, threads_count: usize, exec_policy: ExecPolicy) {
-        let (dir_name, prepared_input) = prepare_input(&input);
-
-        let result = match exec_policy {
-            ExecPolicy::FullPar => {
-                merge_sort_full_par_helper::<T>(prepared_input, max_size_in_ram, Arc::new(Mutex::new(ThreadPool::new(threads_count))))
-            }
-            ExecPolicy::FilePar => {
-                merge_sort_file_par_helper::<T>(prepared_input, max_size_in_ram, Arc::new(Mutex::new(ThreadPool::new(threads_count))))
-            }
-            ExecPolicy::RamPar => {
-                merge_sort_ram_par_helper::<T>(prepared_input, max_size_in_ram, Arc::new(Mutex::new(ThreadPool::new(threads_count))))
-            }
-        };
-
-        clean(&result.path, &output, &dir_name);
-    }
-
-    fn merge_sort_full_par_helper<T: Sort + Channel>(input: FileData, max_size_in_ram: usize, pool: Arc<Mutex<ThreadPool<()>>>) -> FileData {
-        if pool.lock().unwrap().is_available() {
-            merge_sort_full_par_helper_unchecked::<T>(input, max_size_in_ram, pool)
-        } else {
-            merge_sort_seq_helper::<T>(input, max_size_in_ram)
-        }
-    }
-
-    fn merge_sort_full_par_helper_unchecked<T: Sort + Channel>(input: FileData, max_size_in_ram: usize, pool: Arc<Mutex<ThreadPool<()>>>) -> FileData {
-        if input.file.metadata().unwrap().len() < max_size_in_ram as u64 {
-            return compute_in_ram_par::<T>(&input.path, pool);
-        }
-
-        merge_sort_file_par::<T>(merge_sort_full_par_helper::<T>, input, max_size_in_ram, pool)
-    }
-
-    fn merge_sort_file_par_helper<T: Sort + Channel>(input: FileData, max_size_in_ram: usize, pool: Arc<Mutex<ThreadPool<()>>>) -> FileData {
-        if pool.lock().unwrap().is_available() {
-            merge_sort_file_par_helper_unchecked::<T>(input, max_size_in_ram, pool)
-        } else {
-            merge_sort_seq_helper::<T>(input, max_size_in_ram)
-        }
-    }
-
-    fn merge_sort_file_par_helper_unchecked<T: Sort + Channel>(input: FileData, max_size_in_ram: usize, pool: Arc<Mutex<ThreadPool<()>>>) -> FileData {
-        if input.file.metadata().unwrap().len() < max_size_in_ram as u64 {
-            return compute_in_ram_seq::<T>(&input.path);
-        }
-
-        merge_sort_file_par::<T>(merge_sort_file_par_helper::<T>, input, max_size_in_ram, pool)
-    }
-
-    fn merge_sort_ram_par_helper<T: Sort + Channel>(input: FileData, max_size_in_ram: usize, pool: Arc<Mutex<ThreadPool<()>>>) -> FileData {
-        if input.file.metadata().unwrap().len() < max_size_in_ram as u64 {
-            return compute_in_ram_par::<T>(&input.path, pool);
-        }
-
-        let tmp_output_path = String::from(&input.path) + "m";
-
-        match split_file(input) {
-            Ok(files) => {
-                let left_sorted = merge_sort_ram_par_helper::<T>(files.0, max_size_in_ram, Arc::clone(&pool));
-                let right_sorted = merge_sort_ram_par_helper::<T>(files.1, max_size_in_ram, pool);
-
-                let tmp_output = File::create(&tmp_output_path).expect(format!("Couldn't open the file: {}", &tmp_output_path).as_str());
-                merge::<T>(left_sorted, right_sorted, FileData { file: tmp_output, path: tmp_output_path, lines_count: 0 })
-            }
-            Err(unit_file) => unit_file
-        }
-    }
-
-    fn merge_sort_file_par<T: Sort>(func: fn(FileData, usize, Arc<Mutex<ThreadPool<()>>>) -> FileData, input: FileData, max_size_in_ram: usize, pool: Arc<Mutex<ThreadPool<()>>>) -> FileData {
-        let tmp_output_path = String::from(&input.path) + "m";
-
-        match split_file(input) {
-            Ok(files) => {
-                let new_pool = Arc::clone(&pool);
-                let left_sorted = Arc::new(Mutex::new(None));
-                let left_sorted_copy = Arc::clone(&left_sorted);
-
-                let left_task = pool.lock().unwrap().execute(move || {
-                    let _ = (*left_sorted_copy.lock().unwrap()).insert(func(files.0, max_size_in_ram, new_pool));
-                });
-                let right_sorted = func(files.1, max_size_in_ram, Arc::clone(&pool));
-                left_task.recv().unwrap();
-
-                let left_sorted = Arc::try_unwrap(left_sorted).unwrap().into_inner().unwrap().take().unwrap();
-
-                let tmp_output = File::create(&tmp_output_path).expect(format!("Couldn't open the file: {}", &tmp_output_path).as_str());
-                merge::<T>(left_sorted, right_sorted, FileData { file: tmp_output, path: tmp_output_path, lines_count: 0 })
-            }
-            Err(unit_file) => unit_file
-        }
-    }
-
-    fn compute_in_ram_par<T: Sort + Channel>(input_path: &str, pool: Arc<Mutex<ThreadPool<()>>>) -> FileData {
-        let data = file_reader::load_file_to_vec::<T>(&input_path);
-        let output_path = String::from(input_path) + "w";
-        let sorted = ram::merge_sort_par_helper_from_pool(&data, pool);
-        let _ = file_reader::write_from_vec(&output_path, &sorted, "\n");
-        let _ = fs::remove_file(input_path);
-        FileData { file: File::open(&output_path).unwrap(), path: output_path, lines_count: 0 }
-    }
-
-    fn compute_in_ram_seq<T: Sort>(input_path: &str) -> FileData {
-        let data = file_reader::load_file_to_vec::<T>(&input_path);
-        let output_path = String::from(input_path) + "w";
-        let sorted = ram::merge_sort_seq(&data);
-        let _ = file_reader::write_from_vec(&output_path, &sorted, "\n");
-        let _ = fs::remove_file(input_path);
-        FileData { file: File::open(&output_path).unwrap(), path: output_path, lines_count: 0 }
-    }
-
-    fn split_file(input: FileData) -> Result<(FileData, FileData), FileData> {
-        if input.lines_count < 2 {
-            return Err(input);
-        }
-
-        let file1_path = input.path.clone() + "1";
-        let file2_path = input.path.clone() + "2";
-
-        let file1 = File::create(&file1_path).expect(format!("Couldn't open the file: {}", &file1_path).as_str());
-        let file2 = File::create(&file2_path).expect(format!("Couldn't open the file: {}", &file2_path).as_str());
-
-        let mut buffer1 = BufWriter::new(file1);
-        let mut buffer2 = BufWriter::new(file2);
-        let input_buff = BufReader::new(input.file);
-
-        let mut lines_count1 = 0 as usize;
-        let mut lines_count2 = 0 as usize;
-
-        for (nr, line) in input_buff.lines().enumerate() {
-            if nr < input.lines_count / 2 {
-                write_line(&mut buffer1, &line.unwrap());
-                lines_count1 += 1;
-            } else {
-                write_line(&mut buffer2, &line.unwrap());
-                lines_count2 += 1;
-            }
-        }
-
-        let _ = fs::remove_file(input.path);
-
-        Ok((FileData { file: File::open(&file1_path).expect(format!("Couldn't open the file: {}", &file1_path).as_str()), path: file1_path, lines_count: lines_count1 },
-            FileData { file: File::open(&file2_path).expect(format!("Couldn't open the file: {}", &file2_path).as_str()), path: file2_path, lines_count: lines_count2 }))
-    }
-
-    fn merge<T: Sort>(left: FileData, right: FileData, output: FileData) -> FileData {
-        let mut output_buff = BufWriter::new(output.file);
-        let mut left_buff = BufReader::new(left.file);
-        let mut right_buff = BufReader::new(right.file);
-
-        let mut left_el = get_next::<T>(&mut left_buff);
-        let mut right_el = get_next::<T>(&mut right_buff);
-        let mut lines_count = 0 as usize;
-
-        loop {
-            if left_el.is_some() && right_el.is_some() {
-                if left_el < right_el {
-                    write_line(&mut output_buff, &left_el.unwrap().clone().to_string());
-                    left_el = get_next::<T>(&mut left_buff);
-                } else {
-                    write_line(&mut output_buff, &right_el.unwrap().clone().to_string());
-                    right_el = get_next::<T>(&mut right_buff);
-                }
-                lines_count += 1;
-            } else if left_el.is_some() {
-                write_line(&mut output_buff, &left_el.unwrap().clone().to_string());
-                lines_count += 1;
-                break;
-            } else if right_el.is_some() {
-                write_line(&mut output_buff, &right_el.unwrap().clone().to_string());
-                lines_count += 1;
-                break;
-            }
-        }
-
-        lines_count += write_whole_to(&mut left_buff, &mut output_buff);
-        lines_count += write_whole_to(&mut right_buff, &mut output_buff);
-
-        let _ = fs::remove_file(left.path);
-        let _ = fs::remove_file(right.path);
-
-        let result = File::open(&output.path).expect(format!("Couldn't open the file: {}", &output.path).as_str());
-        FileData { file: result, path: output.path, lines_count }
-    }
-
-    fn get_next<T: FromStr>(buffer: &mut BufReader<File>) -> Option<T> {
-        let mut data = String::new();
-        match buffer.read_line(&mut data) {
-            Ok(_) => {
-                match data.trim().parse::<T>() {
-                    Ok(number) => Option::from(number),
-                    Err(_) => None
-                }
-            }
-            Err(_) => None
-        }
-    }
-
-    fn write_whole_to(input: &mut BufReader<File>, output: &mut BufWriter<File>) -> usize {
-        let mut lines_count = 0 as usize;
-        loop {
-            let mut line = String::new();
-            match input.read_line(&mut line) {
-                Ok(size) => {
-                    if size < 2 {
-                        break;
-                    }
-                    let _ = output.write(line.as_bytes());
-                    lines_count += 1;
-                }
-                _ => break
-            }
-        }
-        lines_count
-    }
-
-    fn write_line(output: &mut BufWriter<File>, to_write: &str) {
-        let _ = output.write(to_write.as_bytes());
-        let _ = output.write("\n".as_bytes());
-    }
-
-    fn prepare_input(input: &str) -> (&'static str, FileData) {
-        let dir_name = "__tmp_merge__";
-        let _ = fs::create_dir(&dir_name);
-
-        let copied_input = String::from(dir_name) + MAIN_SEPARATOR_STR + input;
-        let _ = fs::copy(&input, &copied_input);
-        let lines_count = get_lines_count(&copied_input).unwrap();
-
-        (dir_name, FileData {
-            file: File::open(&copied_input).expect(format!("Couldn't open the file: {}", &copied_input).as_str()),
-            path: copied_input,
-            lines_count,
-        })
-    }
-
-    fn clean(result_path: &str, output_path: &str, tmp_dir: &str) {
-        let _ = fs::rename(&result_path, &output_path);
-        let _ = fs::remove_dir(&tmp_dir);
-    }
-}
\ No newline at end of file
+pub mod ram {
+    use crate::cancel::CancelToken;
+    use crate::error::SortError;
+    use crate::thread_pool::{Channel, ThreadPool};
+    use std::sync::{Arc, Mutex};
+
+    /// Values sorted on a thread between two checks of the cancellation
+    const CANCEL_CHECK_LENGTH: usize = 1 << 16;
+
+    pub trait Sort: Clone + PartialOrd + Ord {}
+
+    impl<T: Clone + PartialOrd + Ord> Sort for T {}
+
+    pub fn merge_sort<T: Sort + Channel>(slice: &[T], threads_count: usize) -> Vec<T> {
+        match threads_count {
+            0 | 1 => merge_sort_seq(slice),
+            _ => merge_sort_par(slice, threads_count),
+        }
+    }
+
+    pub fn merge_sort_seq<T: Sort>(slice: &[T]) -> Vec<T> {
+        match slice.len() {
+            0..=1 => slice.to_vec(),
+            2 => {
+                if slice[0] > slice[1] {
+                    return vec![slice[1].clone(), slice[0].clone()];
+                }
+                slice.to_vec()
+            }
+            3..=100 => {
+                let mut result = slice.to_vec();
+                result.sort();
+                result
+            }
+            _ => {
+                let middle = slice.len() / 2;
+                let left_sorted = merge_sort_seq(&slice[0..middle]);
+                let right_sorted = merge_sort_seq(&slice[middle..]);
+                merge(&left_sorted, &right_sorted)
+            }
+        }
+    }
+
+    /// Same as `merge_sort_seq`, failing with `SortError::Cancelled` once `cancel` is cancelled.
+    /// The token is checked every `CANCEL_CHECK_LENGTH` values at most.
+    pub fn merge_sort_seq_cancellable<T: Sort>(slice: &[T], cancel: &CancelToken) -> Result<Vec<T>, SortError> {
+        cancel.check()?;
+        if slice.len() <= CANCEL_CHECK_LENGTH {
+            return Ok(merge_sort_seq(slice));
+        }
+
+        let middle = slice.len() / 2;
+        let left_sorted = merge_sort_seq_cancellable(&slice[0..middle], cancel)?;
+        let right_sorted = merge_sort_seq_cancellable(&slice[middle..], cancel)?;
+        Ok(merge(&left_sorted, &right_sorted))
+    }
+
+    pub fn merge_sort_par<T: Sort + Channel>(slice: &[T], threads_count: usize) -> Vec<T> {
+        // a new token is never cancelled
+        merge_sort_par_helper_from_pool(slice, Arc::new(Mutex::new(ThreadPool::new(threads_count))), &CancelToken::new()).unwrap()
+    }
+
+    /// Sorts the halves on the workers of the pool while some are available, failing with `SortError::Cancelled`
+    /// once `cancel` is cancelled
+    pub fn merge_sort_par_helper_from_pool<T: Sort + Channel>(slice: &[T], pool: Arc<Mutex<ThreadPool<()>>>, cancel: &CancelToken) -> Result<Vec<T>, SortError> {
+        cancel.check()?;
+        if slice.len() <= 2 {
+            return Ok(merge_sort_seq(slice));
+        }
+
+        // the job is sent while the pool is still locked, so it gets the available worker
+        let locked_pool = pool.lock().unwrap();
+        if !locked_pool.is_available() {
+            drop(locked_pool);
+            return merge_sort_seq_cancellable(slice, cancel);
+        }
+
+        let middle = slice.len() / 2;
+        let left = slice[0..middle].to_vec();
+        let new_pool = Arc::clone(&pool);
+        let new_cancel = cancel.clone();
+        let left_sorted = Arc::new(Mutex::new(None));
+        let left_sorted_copy = Arc::clone(&left_sorted);
+        let result = locked_pool.execute(move || {
+            let _ = (*left_sorted_copy.lock().unwrap()).insert(merge_sort_par_helper_from_pool(&left, new_pool, &new_cancel));
+        });
+        drop(locked_pool);
+        let right_sorted = merge_sort_par_helper_from_pool(&slice[middle..], Arc::clone(&pool), cancel);
+        result.recv().unwrap();
+        // the job is only skipped by a pool once the token is cancelled
+        let left_sorted = Arc::into_inner(left_sorted).unwrap().into_inner().unwrap().unwrap_or(Err(SortError::Cancelled))?;
+
+        Ok(merge(&left_sorted, &right_sorted?))
+    }
+
+    fn merge<T: Sort>(left: &[T], right: &[T]) -> Vec<T> {
+        let mut left_pos = 0;
+        let mut right_pos = 0;
+        let mut merged: Vec<T> = Vec::with_capacity(left.len() + right.len());
+
+        while left_pos != left.len() && right_pos < right.len() {
+            if left[left_pos] < right[right_pos] {
+                merged.push(left[left_pos].clone());
+                left_pos += 1;
+            } else {
+                merged.push(right[right_pos].clone());
+                right_pos += 1;
+            }
+        }
+
+        if left_pos < left.len() {
+            merged.extend_from_slice(&left[left_pos..]);
+        }
+
+        if right_pos < right.len() {
+            merged.extend_from_slice(&right[right_pos..]);
+        }
+
+        merged
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::thread::available_parallelism;
+        use super::*;
+
+        #[test]
+        fn test_merge() {
+            let merged = merge([1, 4, 8, 10].as_slice(), [2, 3, 8, 9].as_slice());
+            assert_eq!(merged, vec![1, 2, 3, 4, 8, 8, 9, 10]);
+        }
+
+        #[test]
+        fn test_merge_sort_seq() {
+            let unsorted = vec![5, 1, 9, 10, 3, 45, 2, 4, 4, 12];
+            let mut sorted = unsorted.clone();
+            sorted.sort();
+            assert_eq!(merge_sort(&unsorted, 1), sorted)
+        }
+
+        #[test]
+        fn test_merge_sort_par() {
+            let unsorted = vec![5, 1, 9, 10, 3, 45, 2, 4, 4, 12];
+            let mut sorted = unsorted.clone();
+            sorted.sort();
+            assert_eq!(merge_sort(&unsorted, available_parallelism().unwrap().get()), sorted)
+        }
+
+        #[test]
+        fn test_cancelled_merge_sort() {
+            let unsorted: Vec<u32> = (0..200_000).rev().collect();
+            let cancel = CancelToken::new();
+            assert_eq!(merge_sort_seq_cancellable(&unsorted, &cancel).unwrap(), merge_sort_seq(&unsorted));
+            cancel.cancel();
+            assert!(matches!(merge_sort_seq_cancellable(&unsorted, &cancel), Err(SortError::Cancelled)));
+            let pool = Arc::new(Mutex::new(ThreadPool::cancellable(4, cancel.clone())));
+            assert!(matches!(merge_sort_par_helper_from_pool(&unsorted, pool, &cancel), Err(SortError::Cancelled)));
+        }
+    }
+}
+
+pub mod file {
+    use std::fs;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+    use std::marker::PhantomData;
+    use std::ops::Range;
+    use std::path::{Path, MAIN_SEPARATOR_STR};
+    use std::process;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use crate::block_io::{self, IoOptions, PrefetchReader, WriteBehind};
+    use crate::cancel::CancelToken;
+    use crate::element::{Element, Format};
+    use crate::encoding::{BinaryEncoding, Encoding, TextEncoding, INDEX_INTERVAL};
+    use crate::disk::{self, Holes, TempQuota};
+    use crate::error::SortError;
+    use crate::file_reader::STDIO_PATH;
+    use crate::memory::{MemoryBudget, MemoryModel, SAMPLE_SIZE};
+    use crate::progress::{Phase, Progress};
+    use crate::sorter::ExternalSorter;
+
+    use crate::merge_sorter::ram;
+    use crate::thread_pool::{Channel, ThreadPool};
+
+    pub trait Sort: ram::Sort + Element + Format + std::fmt::Debug {}
+
+    impl<T: ram::Sort + Element + Format + std::fmt::Debug> Sort for T {}
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[allow(clippy::enum_variant_names)]
+    pub enum ExecPolicy {
+        FullPar,
+        FilePar,
+        RamPar,
+        Pipelined,
+    }
+
+    impl FromStr for ExecPolicy {
+        type Err = &'static str;
+
+        fn from_str(input: &str) -> Result<ExecPolicy, Self::Err> {
+            match input {
+                "FullPar" => Ok(ExecPolicy::FullPar),
+                "FilePar" => Ok(ExecPolicy::FilePar),
+                "RamPar" => Ok(ExecPolicy::RamPar),
+                "Pipelined" => Ok(ExecPolicy::Pipelined),
+                _ => Err("Wrong value"),
+            }
+        }
+    }
+
+    static INPUTS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    /// Both inputs of a merge and its output
+    const MERGE_STREAMS: usize = 3;
+
+    /// Values merged between two checks of the cancellation and two updates of the progress
+    const MERGE_CHECK_INTERVAL: usize = 1 << 12;
+
+    const TMP_DIR: &str = "__tmp_merge__";
+
+    /// Names of the streams in the errors
+    const INPUT_STREAM: &str = "<input stream>";
+    const OUTPUT_STREAM: &str = "<output stream>";
+    const STDIN: &str = "<standard input>";
+    const STDOUT: &str = "<standard output>";
+
+    type SortHelper<E> = fn(FileData, usize, &Context<E>, Arc<Mutex<ThreadPool<()>>>) -> Result<FileData, SortError>;
+
+    /// Settings shared by all the steps of a sort
+    #[derive(Clone)]
+    struct Context<E> {
+        encoding: E,
+        io_options: IoOptions,
+        memory: MemoryModel,
+        /// Limit of the heap bytes taken at once by all the threads of the sort
+        budget: Arc<MemoryBudget>,
+        /// Limit of the bytes of temporary files kept at once
+        quota: Arc<TempQuota>,
+        /// Whether the blocks of temporary files are freed as soon as they are read
+        low_disk: bool,
+        /// Checked before every step and while values are read and merged
+        cancel: CancelToken,
+        progress: Progress,
+    }
+
+    impl<E> Context<E> {
+        /// Estimates the memory taken by the values from the start of the input
+        fn new<T>(encoding: E, io_options: IoOptions, input: &FileData, max_size_in_ram: usize, quota: Arc<TempQuota>) -> Result<Context<E>, SortError>
+            where
+                E: Encoding<T>,
+        {
+            let mut reader = BufReader::new(input.open()?);
+            let range = &input.range;
+            let sample = encoding.split_point(&mut reader, range, range.start + SAMPLE_SIZE)
+                .and_then(|end| {
+                    let mut data = vec![0; (end - range.start) as usize];
+                    reader.seek(SeekFrom::Start(range.start))?;
+                    reader.read_exact(&mut data)?;
+                    Ok(data)
+                })
+                .map_err(SortError::io(input.path()))?;
+
+            Context::from_sample::<T>(encoding, io_options, &sample, input.path(), max_size_in_ram, quota)
+        }
+
+        /// Estimates the memory taken by the values from a sample of them read from `path`
+        fn from_sample<T>(encoding: E, io_options: IoOptions, sample: &[u8], path: &str, max_size_in_ram: usize, quota: Arc<TempQuota>) -> Result<Context<E>, SortError>
+            where
+                E: Encoding<T>,
+        {
+            let memory = MemoryModel::new(encoding.sample(sample).map_err(SortError::parse(path))?);
+            // merges take at most a quarter of the budget for their blocks
+            let io_options = io_options.limit_buffers(MERGE_STREAMS, max_size_in_ram / 4);
+            Ok(Context { encoding, io_options, memory, budget: Arc::new(MemoryBudget::new(max_size_in_ram)), quota, low_disk: false, cancel: CancelToken::new(), progress: Progress::new() })
+        }
+
+        /// Frees the blocks of the temporary files as they are read, their values being read only once.
+        /// The merge outputs aren't preallocated then, since their inputs shrink while they grow.
+        fn set_low_disk(&mut self, low_disk: bool) {
+            self.low_disk = low_disk;
+            self.io_options.preallocate &= !low_disk;
+        }
+
+        /// Reader of values that are read only once
+        fn reader(&self, input: &FileData) -> Result<PrefetchReader, SortError> {
+            if self.low_disk {
+                input.consuming_reader(&self.io_options)
+            } else {
+                input.reader(&self.io_options)
+            }
+        }
+
+        /// Whether the values fit in RAM while being sorted on `threads` threads
+        fn fits_in_ram(&self, input: &FileData, max_size_in_ram: usize, threads: usize) -> bool {
+            self.memory.in_ram_size(input.size(), threads) < max_size_in_ram as u64
+        }
+    }
+
+    /// File holding values to sort. Temporary files are removed once no part of them is needed anymore,
+    /// the input of a sort is only read, unless it is consumed in the low disk mode.
+    #[derive(Debug)]
+    struct SourceFile {
+        path: String,
+        temporary: bool,
+        /// Bytes of the quota taken by a temporary file, given back as its blocks are freed or once it is removed
+        charge: Option<(Arc<TempQuota>, AtomicU64)>,
+        /// Ranges released so far of a temporary file once it is written, or of a consumed input
+        holes: Option<Mutex<Holes>>,
+    }
+
+    impl SourceFile {
+        /// Frees the blocks of a byte range of the file, opened for writing, that won't be read anymore.
+        /// Where the file system can't punch holes, they are kept until the file is removed.
+        fn release(&self, file: &File, range: Range<u64>) {
+            let Some(holes) = &self.holes else {
+                return;
+            };
+            // the ranges are punched one at a time, so that every block freed is given back once
+            let mut holes = holes.lock().unwrap();
+            let (punched, bytes) = holes.release(range);
+            if bytes == 0 || block_io::punch_hole(file, punched).is_err() {
+                return;
+            }
+            if let Some((quota, charged)) = &self.charge {
+                let previous = charged.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |charged| Some(charged.saturating_sub(bytes))).unwrap();
+                quota.give_back(u64::min(previous, bytes));
+            }
+        }
+
+        /// Keeps the file when it is dropped, returning its path
+        fn keep(mut self) -> String {
+            self.temporary = false;
+            std::mem::take(&mut self.path)
+        }
+    }
+
+    impl Drop for SourceFile {
+        fn drop(&mut self) {
+            if self.temporary {
+                let _ = fs::remove_file(&self.path);
+            }
+            if let Some((quota, bytes)) = &self.charge {
+                quota.give_back(bytes.load(Ordering::Relaxed));
+            }
+        }
+    }
+
+    /// Values held in a byte range of a file, which starts and ends at the boundaries of values
+    #[derive(Debug)]
+    struct FileData {
+        source: Arc<SourceFile>,
+        range: Range<u64>,
+        /// Prefix of the paths of the temporary files made from these values
+        name: String,
+        /// Merges the values went through
+        level: u32,
+    }
+
+    impl FileData {
+        /// Whole temporary file written by `write`, about `size` bytes being taken from the quota beforehand.
+        /// It is removed when it can't be written.
+        fn temporary(path: String, size: u64, quota: &Arc<TempQuota>, write: impl FnOnce(&str) -> Result<(), SortError>) -> Result<FileData, SortError> {
+            quota.take(size)?;
+            let mut source = SourceFile { path, temporary: true, charge: Some((Arc::clone(quota), AtomicU64::new(size))), holes: None };
+            write(&source.path)?;
+            let metadata = fs::metadata(&source.path).map_err(SortError::io(&source.path))?;
+            let written = metadata.len();
+            if written > size {
+                quota.take(written - size)?;
+            } else {
+                quota.give_back(size - written);
+            }
+            source.charge = Some((Arc::clone(quota), AtomicU64::new(written)));
+            source.holes = Some(Mutex::new(Holes::new(disk::block_size(&metadata), written)));
+            Ok(FileData { name: source.path.clone(), source: Arc::new(source), range: 0..written, level: 0 })
+        }
+
+        fn part(&self, range: Range<u64>, suffix: &str) -> FileData {
+            FileData { source: Arc::clone(&self.source), range, name: self.name.clone() + suffix, level: self.level }
+        }
+
+        fn path(&self) -> &str {
+            &self.source.path
+        }
+
+        fn size(&self) -> u64 {
+            self.range.end - self.range.start
+        }
+
+        fn open(&self) -> Result<File, SortError> {
+            File::open(self.path()).map_err(SortError::io(self.path()))
+        }
+
+        fn reader(&self, io_options: &IoOptions) -> Result<PrefetchReader, SortError> {
+            Ok(PrefetchReader::new(self.open()?, self.range.clone(), io_options))
+        }
+
+        /// Lets the blocks of the values be freed once they are in runs. The input of a sort only allows it
+        /// when it can be written to, on a file system that punches holes. Returns whether they can be freed.
+        fn consume(&mut self) -> bool {
+            if self.source.holes.is_some() {
+                return true;
+            }
+            let dir = parent_dir(Path::new(self.path())).to_string_lossy().into_owned();
+            let metadata = OpenOptions::new().write(true).open(self.path()).and_then(|file| file.metadata());
+            match (Arc::get_mut(&mut self.source), metadata) {
+                (Some(source), Ok(metadata)) if disk::can_punch_holes(&dir) => {
+                    source.holes = Some(Mutex::new(Holes::new(disk::block_size(&metadata), metadata.len())));
+                    true
+                }
+                _ => false,
+            }
+        }
+
+        /// Reader freeing the blocks of a temporary file as they are read
+        fn consuming_reader(&self, io_options: &IoOptions) -> Result<PrefetchReader, SortError> {
+            if !self.source.temporary {
+                return self.reader(io_options);
+            }
+            let file = OpenOptions::new().read(true).write(true).open(self.path()).map_err(SortError::io(self.path()))?;
+            let source = Arc::clone(&self.source);
+            Ok(PrefetchReader::consuming(file, self.range.clone(), io_options, Box::new(move |file, range| source.release(file, range))))
+        }
+
+        /// Frees the blocks of the values once they are in a run, when they are in a temporary file or a consumed input
+        fn release(&self) {
+            if self.source.holes.is_none() {
+                return;
+            }
+            if let Ok(file) = OpenOptions::new().write(true).open(self.path()) {
+                self.source.release(&file, self.range.clone());
+            }
+        }
+    }
+
+    pub(crate) fn merge_sort<T: Sort + Channel>(input: &str, output: &str, sorter: &ExternalSorter) -> Result<(), SortError> {
+        if input == STDIO_PATH || output == STDIO_PATH {
+            return merge_sort_stdio::<T>(input, output, sorter);
+        }
+
+        let mut output = output.to_string();
+        if is_same_file(input, &output) {
+            if !sorter.in_place {
+                return Err(SortError::OutputIsInput { path: output });
+            }
+            // the file is replaced instead of a link to it
+            output = fs::canonicalize(&output).map_err(SortError::io(&output))?.to_string_lossy().into_owned();
+        }
+
+        let tmp_dir = create_tmp_dir(sorter)?;
+        let quota = Arc::new(TempQuota::new(sorter.max_temp_bytes));
+        let encoding = TextEncoding { options: sorter.parse_options.clone() };
+        let result = prepare_input(input, &tmp_dir)
+            .and_then(|prepared_input| sort_prepared::<T, TextEncoding>(prepared_input, encoding, sorter, &quota, &tmp_dir, Some(&output)));
+
+        clean(result, &output, &tmp_dir, sorter)
+    }
+
+    pub(crate) fn merge_sort_stream<T: Sort + Channel, R: Read, W: Write>(input: R, output: W, sorter: &ExternalSorter) -> Result<(), SortError> {
+        let tmp_dir = create_tmp_dir(sorter)?;
+        let result = sort_stream_to::<T, _, W>(BufReader::new(input), INPUT_STREAM, output, OUTPUT_STREAM, &tmp_dir, sorter);
+
+        remove_tmp_dir(&tmp_dir);
+        result
+    }
+
+    /// Sorts from the standard input or to the standard output, the other one being a file
+    fn merge_sort_stdio<T: Sort + Channel>(input: &str, output: &str, sorter: &ExternalSorter) -> Result<(), SortError> {
+        let tmp_dir = create_tmp_dir(sorter)?;
+        let input_name = if input == STDIO_PATH { STDIN } else { input };
+        let reader: Result<Box<dyn BufRead>, SortError> = if input == STDIO_PATH {
+            Ok(Box::new(io::stdin().lock()))
+        } else {
+            File::open(input).map(|file| Box::new(BufReader::new(file)) as Box<dyn BufRead>).map_err(SortError::io(input))
+        };
+
+        if output == STDIO_PATH {
+            let result = reader.and_then(|reader| sort_stream_to::<T, _, _>(reader, input_name, io::stdout().lock(), STDOUT, &tmp_dir, sorter));
+            remove_tmp_dir(&tmp_dir);
+            result
+        } else {
+            let result = reader.and_then(|reader| sort_stream_prepared::<T, _>(reader, input_name, &tmp_dir, sorter));
+            clean(result, output, &tmp_dir, sorter)
+        }
+    }
+
+    pub(crate) fn merge_sort_serde<T, I>(input: I, sorter: &ExternalSorter) -> Result<SortedIter<T>, SortError>
+        where
+            T: ram::Sort + Channel + Serialize + DeserializeOwned,
+            I: IntoIterator<Item = T>,
+    {
+        let tmp_dir = create_tmp_dir(sorter)?;
+        let encoding = BinaryEncoding::<T>::new();
+        let quota = Arc::new(TempQuota::new(sorter.max_temp_bytes));
+        // the size of the values is only known once they are written
+        let mut offsets = Vec::new();
+        let result = FileData::temporary(unique_path(&tmp_dir, "serde_input"), 0, &quota, |path| {
+                File::create(path)
+                    .and_then(|file| {
+                        let mut output = CountingWriter { output: &mut BufWriter::new(file), bytes: 0 };
+                        for value in input {
+                            // the splits of the input start going through the lengths from the value before their middle
+                            if output.bytes >= offsets.len() as u64 * INDEX_INTERVAL {
+                                offsets.push(output.bytes);
+                            }
+                            encoding.write_next(&mut output, &value)?;
+                        }
+                        output.flush()
+                    })
+                    .map_err(SortError::io(path))
+            })
+            .and_then(|prepared_input| sort_prepared::<T, BinaryEncoding<T>>(prepared_input, BinaryEncoding::indexed(offsets), sorter, &quota, &tmp_dir, None))
+            .and_then(|result| Ok((result.open()?, result)));
+        // the sorted values are written to the iterator as it is read
+        sorter.progress.finish();
+        let (file, result) = result.inspect_err(|_| remove_tmp_dir(&tmp_dir))?;
+
+        let path = Arc::into_inner(result.source).unwrap().keep();
+        Ok(SortedIter { input: BufReader::new(file), buffer: Vec::new(), path, tmp_dir, encoding, marker: PhantomData })
+    }
+
+    /// Sorted values read back from the last temporary file, which is removed when the iterator is dropped
+    pub struct SortedIter<T: Serialize + DeserializeOwned + 'static> {
+        input: BufReader<File>,
+        buffer: Vec<u8>,
+        path: String,
+        tmp_dir: String,
+        encoding: BinaryEncoding<T>,
+        marker: PhantomData<T>,
+    }
+
+    impl<T: Serialize + DeserializeOwned + 'static> Iterator for SortedIter<T> {
+        type Item = Result<T, SortError>;
+
+        fn next(&mut self) -> Option<Result<T, SortError>> {
+            self.encoding.read_next(&mut self.input, &mut self.buffer).map_err(SortError::io(&self.path)).transpose()
+        }
+    }
+
+    impl<T: Serialize + DeserializeOwned + 'static> Drop for SortedIter<T> {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+            remove_tmp_dir(&self.tmp_dir);
+        }
+    }
+
+    /// Sorts the input to a temporary file, which is later moved to `output` when given
+    fn sort_prepared<T: ram::Sort + Channel, E: Encoding<T>>(mut input: FileData, encoding: E, sorter: &ExternalSorter, quota: &Arc<TempQuota>, tmp_dir: &str, output: Option<&str>) -> Result<FileData, SortError> {
+        let max_size_in_ram = sorter.max_size_in_ram;
+        let mut context = Context::new::<T>(encoding, sorter.io_options, &input, max_size_in_ram, Arc::clone(quota))?;
+        context.cancel = sorter.cancel.clone();
+        context.progress = sorter.progress.clone();
+        let threads = usize::max(1, sorter.threads_count);
+        let in_ram = context.fits_in_ram(&input, max_size_in_ram, threads);
+        context.set_low_disk(check_space(&mut input, in_ram, sorter.low_disk, tmp_dir, output, quota)?);
+        // the input is halved until its parts fit in RAM, every halving adding a merge level
+        let parts = context.memory.in_ram_size(input.size(), threads).div_ceil(u64::max(1, max_size_in_ram as u64));
+        context.progress.start(Some(input.size()), parts.next_power_of_two().trailing_zeros());
+
+        match sorter.threads_count {
+            0 | 1 => merge_sort_seq_helper::<T, E>(input, max_size_in_ram, &context),
+            threads_count => merge_sort_par_prepared::<T, E>(input, max_size_in_ram, threads_count, sorter.exec_policy, &context),
+        }
+    }
+
+    fn merge_sort_par_prepared<T: ram::Sort + Channel, E: Encoding<T>>(input: FileData, max_size_in_ram: usize, threads_count: usize, exec_policy: ExecPolicy, context: &Context<E>) -> Result<FileData, SortError> {
+        let pool = Arc::new(Mutex::new(ThreadPool::cancellable(threads_count, context.cancel.clone())));
+        let result = match exec_policy {
+            ExecPolicy::FullPar => merge_sort_full_par_helper::<T, E>(input, max_size_in_ram, context, Arc::clone(&pool)),
+            // the values sorted in RAM by every thread have to fit in the budget at once
+            ExecPolicy::FilePar => merge_sort_file_par_helper::<T, E>(input, max_size_in_ram / threads_count, context, Arc::clone(&pool)),
+            ExecPolicy::RamPar => merge_sort_ram_par_helper::<T, E>(input, max_size_in_ram, context, Arc::clone(&pool)),
+            ExecPolicy::Pipelined => merge_sort_pipelined::<T, E>(input, max_size_in_ram, context, Arc::clone(&pool)),
+        };
+        add_threads(&context.progress, &pool);
+        result
+    }
+
+    /// Counts the time the workers of the pool were busy, once all its jobs are done
+    fn add_threads(progress: &Progress, pool: &Mutex<ThreadPool<()>>) {
+        let pool = pool.lock().unwrap();
+        progress.add_threads(pool.size(), pool.busy_time());
+    }
+
+    fn merge_sort_seq_helper<T: ram::Sort + Channel, E: Encoding<T>>(input: FileData, max_size_in_ram: usize, context: &Context<E>) -> Result<FileData, SortError> {
+        if context.fits_in_ram(&input, max_size_in_ram, 1) {
+            return compute_in_ram_seq::<T, E>(input, context);
+        }
+
+        match split_file::<T, E>(&input, context)? {
+            Some(files) => {
+                let tmp_output_path = input.name.clone() + "m";
+                drop(input);
+                let left_sorted = merge_sort_seq_helper::<T, E>(files.0, max_size_in_ram, context)?;
+                let right_sorted = merge_sort_seq_helper::<T, E>(files.1, max_size_in_ram, context)?;
+
+                merge::<T, E>(left_sorted, right_sorted, tmp_output_path, context)
+            }
+            None => compute_in_ram_seq::<T, E>(input, context)
+        }
+    }
+
+    fn merge_sort_full_par_helper<T: ram::Sort + Channel, E: Encoding<T>>(input: FileData, max_size_in_ram: usize, context: &Context<E>, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<FileData, SortError> {
+        let threads = pool.lock().unwrap().size();
+        if context.fits_in_ram(&input, max_size_in_ram, threads) {
+            return compute_in_ram_par::<T, E>(input, context, pool);
+        }
+
+        merge_sort_file_par::<T, E>(merge_sort_full_par_helper::<T, E>, input, max_size_in_ram, context, pool)
+    }
+
+    fn merge_sort_file_par_helper<T: ram::Sort + Channel, E: Encoding<T>>(input: FileData, max_size_in_ram: usize, context: &Context<E>, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<FileData, SortError> {
+        if context.fits_in_ram(&input, max_size_in_ram, 1) {
+            return compute_in_ram_seq::<T, E>(input, context);
+        }
+
+        merge_sort_file_par::<T, E>(merge_sort_file_par_helper::<T, E>, input, max_size_in_ram, context, pool)
+    }
+
+    fn merge_sort_ram_par_helper<T: ram::Sort + Channel, E: Encoding<T>>(input: FileData, max_size_in_ram: usize, context: &Context<E>, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<FileData, SortError> {
+        let threads = pool.lock().unwrap().size();
+        if context.fits_in_ram(&input, max_size_in_ram, threads) {
+            return compute_in_ram_par::<T, E>(input, context, pool);
+        }
+
+        match split_file::<T, E>(&input, context)? {
+            Some(files) => {
+                let tmp_output_path = input.name.clone() + "m";
+                drop(input);
+                let left_sorted = merge_sort_ram_par_helper::<T, E>(files.0, max_size_in_ram, context, Arc::clone(&pool))?;
+                let right_sorted = merge_sort_ram_par_helper::<T, E>(files.1, max_size_in_ram, context, pool)?;
+
+                merge::<T, E>(left_sorted, right_sorted, tmp_output_path, context)
+            }
+            None => compute_in_ram_par::<T, E>(input, context, pool)
+        }
+    }
+
+    fn merge_sort_file_par<T: ram::Sort + Channel, E: Encoding<T>>(func: SortHelper<E>, input: FileData, max_size_in_ram: usize, context: &Context<E>, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<FileData, SortError> {
+        match split_file::<T, E>(&input, context)? {
+            Some(files) => {
+                let tmp_output_path = input.name.clone() + "m";
+                drop(input);
+                // the job is sent while the pool is still locked, so it gets the available worker
+                // instead of waiting behind jobs that may wait for the memory budget
+                let locked_pool = pool.lock().unwrap();
+                if !locked_pool.is_available() {
+                    drop(locked_pool);
+                    let left_sorted = func(files.0, max_size_in_ram, context, Arc::clone(&pool))?;
+                    let right_sorted = func(files.1, max_size_in_ram, context, pool)?;
+                    return merge::<T, E>(left_sorted, right_sorted, tmp_output_path, context);
+                }
+
+                let new_pool = Arc::clone(&pool);
+                let new_context = context.clone();
+                let left_sorted = Arc::new(Mutex::new(None));
+                let left_sorted_copy = Arc::clone(&left_sorted);
+                let left_task = locked_pool.execute(move || {
+                    let _ = (*left_sorted_copy.lock().unwrap()).insert(func(files.0, max_size_in_ram, &new_context, new_pool));
+                });
+                drop(locked_pool);
+                let right_sorted = func(files.1, max_size_in_ram, context, Arc::clone(&pool));
+                left_task.recv().unwrap();
+
+                // both halves are done before an error is returned, so none of them writes to the temporary directory anymore
+                // the job is only skipped by the pool once the sort is cancelled
+                let left_sorted = Arc::try_unwrap(left_sorted).unwrap().into_inner().unwrap().unwrap_or(Err(SortError::Cancelled))?;
+                merge::<T, E>(left_sorted, right_sorted?, tmp_output_path, context)
+            }
+            None => compute_in_ram_par::<T, E>(input, context, pool)
+        }
+    }
+
+    /// Reading, parsing, sorting and writing stages, each holding a chunk of the input at a time
+    const PIPELINE_STAGES: usize = 4;
+    /// Chunks of input held at once: the one being read, the one waiting to be parsed and the one being parsed
+    const PIPELINE_CHUNKS: usize = 3;
+    /// Copies of the values of a chunk held at once: the ones being parsed, waiting to be sorted,
+    /// being sorted with their sorted halves and merge, waiting to be written and being written
+    const PIPELINE_VALUES_COPIES: usize = 7;
+
+    /// Cuts the input into runs sorted in RAM and merges them
+    fn merge_sort_pipelined<T: ram::Sort + Channel, E: Encoding<T>>(input: FileData, max_size_in_ram: usize, context: &Context<E>, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<FileData, SortError> {
+        let threads = pool.lock().unwrap().size();
+        if context.fits_in_ram(&input, max_size_in_ram, threads) {
+            return compute_in_ram_par::<T, E>(input, context, pool);
+        }
+
+        let output_path = input.name.clone() + "m";
+        // the input is only freed once its values are in runs, not as it is read
+        let reader = input.reader(&context.io_options)?;
+        let runs = form_runs::<T, E, _>(reader, input.path(), Some(&input), &input.name, max_size_in_ram, context, Arc::clone(&pool))?;
+        drop(input);
+        merge_runs::<T, E>(runs, output_path, context, pool)
+    }
+
+    /// Cuts the values read from `input` into runs sorted in RAM, taking the whole budget. The runs are formed by a pipeline:
+    /// while a chunk is sorted, the next one is read and parsed and the previous one is written.
+    /// The stages stop at the first error, which is kept in `error`.
+    /// The stages before the failed one stop when they can't send to it anymore.
+    /// `input` reads the values of `file` when there is one, whose range locates the values that fail to parse
+    /// and whose blocks are freed as the runs are written in the low disk mode.
+    fn form_runs<T: ram::Sort + Channel, E: Encoding<T>, R: BufRead>(mut input: R, input_path: &str, file: Option<&FileData>, name: &str, max_size_in_ram: usize, context: &Context<E>, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<Vec<FileData>, SortError> {
+        let chunks_budget = max_size_in_ram.saturating_sub(context.io_options.stream_buffers_size());
+        let chunk_size = usize::max(1, context.memory.chunk_size(chunks_budget, PIPELINE_CHUNKS, PIPELINE_VALUES_COPIES));
+        let _reservation = context.budget.reserve(max_size_in_ram);
+        context.progress.begin(Phase::Read);
+
+        let stages = ThreadPool::<()>::new(PIPELINE_STAGES - 1);
+        let (chunk_sender, chunk_receiver) = mpsc::sync_channel::<Vec<u8>>(1);
+        // the values are sent with the size of their chunk, which their run takes about
+        let (parsed_sender, parsed_receiver) = mpsc::sync_channel::<(Vec<T>, u64)>(1);
+        let (sorted_sender, sorted_receiver) = mpsc::sync_channel::<(Vec<T>, u64)>(1);
+        let error = Arc::new(Mutex::new(None));
+
+        // receivers are not `Sync`, which the jobs of the pool have to be
+        let chunk_receiver = Mutex::new(chunk_receiver);
+        let parsed_receiver = Mutex::new(parsed_receiver);
+        let sorted_receiver = Mutex::new(sorted_receiver);
+
+        let mut offset = file.map_or(0, |file| file.range.start);
+        let parser_encoding = context.encoding.clone();
+        let parsed_path = input_path.to_string();
+        let parse_error = Arc::clone(&error);
+        let parse_progress = context.progress.clone();
+        let parse = stages.execute(move || {
+            for chunk in chunk_receiver.into_inner().unwrap() {
+                match parser_encoding.decode(&chunk).map_err(|err| err.shifted(offset)) {
+                    Ok(parsed) => {
+                        offset += chunk.len() as u64;
+                        parse_progress.add(Phase::Read, chunk.len() as u64, parsed.len() as u64);
+                        if parsed_sender.send((parsed, chunk.len() as u64)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        parse_error.lock().unwrap().get_or_insert(SortError::parse(&parsed_path)(err));
+                        break;
+                    }
+                }
+            }
+        });
+
+        let sort_progress = context.progress.clone();
+        let sort_error = Arc::clone(&error);
+        let sort_cancel = context.cancel.clone();
+        let sort = stages.execute(move || {
+            for (parsed, size) in parsed_receiver.into_inner().unwrap() {
+                sort_progress.begin(Phase::Sort);
+                let sorted = ram::merge_sort_par_helper_from_pool(&parsed, Arc::clone(&pool), &sort_cancel);
+                drop(parsed);
+                match sorted {
+                    Ok(sorted) => {
+                        if sorted_sender.send((sorted, size)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        sort_error.lock().unwrap().get_or_insert(err);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let writer_encoding = context.encoding.clone();
+        let runs_path = name.to_string() + "r";
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let runs_copy = Arc::clone(&runs);
+        let write_error = Arc::clone(&error);
+        let quota = Arc::clone(&context.quota);
+        let write_progress = context.progress.clone();
+        let mut consumed = file.filter(|_| context.low_disk).map(|file| file.part(file.range.clone(), ""));
+        let write = stages.execute(move || {
+            for (index, (sorted, size)) in sorted_receiver.into_inner().unwrap().into_iter().enumerate() {
+                match FileData::temporary(format!("{}{}", runs_path, index), size, &quota, |path| writer_encoding.store(path, &sorted)) {
+                    Ok(run) => {
+                        write_progress.add(Phase::Sort, run.size(), sorted.len() as u64);
+                        write_progress.add_file(Phase::Sort);
+                        runs_copy.lock().unwrap().push(run);
+                        // the chunks are read in order, so the values of the run are the next bytes of the file
+                        if let Some(file) = &mut consumed {
+                            let end = u64::min(file.range.start + size, file.range.end);
+                            file.part(file.range.start..end, "").release();
+                            file.range.start = end;
+                        }
+                    }
+                    Err(err) => {
+                        write_error.lock().unwrap().get_or_insert(err);
+                        break;
+                    }
+                }
+            }
+        });
+
+        // the values are read on the calling thread, so the input doesn't have to be sent to another one
+        let mut line = Vec::new();
+        loop {
+            if let Err(err) = context.cancel.check() {
+                error.lock().unwrap().get_or_insert(err);
+                break;
+            }
+            let mut chunk = Vec::with_capacity(chunk_size);
+            let mut copied = Ok(true);
+            while chunk.len() < chunk_size && matches!(copied, Ok(true)) {
+                copied = context.encoding.copy_next(&mut input, &mut chunk, &mut line);
+            }
+            if let Err(err) = copied {
+                error.lock().unwrap().get_or_insert(SortError::io(input_path)(err));
+                break;
+            }
+            if chunk.is_empty() || chunk_sender.send(chunk).is_err() {
+                break;
+            }
+        }
+        drop(chunk_sender);
+
+        parse.recv().unwrap();
+        sort.recv().unwrap();
+        write.recv().unwrap();
+
+        match Arc::into_inner(error).unwrap().into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(Arc::into_inner(runs).unwrap().into_inner().unwrap()),
+        }
+    }
+
+    /// Merges the runs into a single temporary file
+    fn merge_runs<T: ram::Sort + Channel, E: Encoding<T>>(runs: Vec<FileData>, output_path: String, context: &Context<E>, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<FileData, SortError> {
+        match merge_rounds::<T, E>(runs, 1, context, pool)?.pop() {
+            Some(result) => Ok(result),
+            None => FileData::temporary(output_path, 0, &context.quota, |path| File::create(path).map(drop).map_err(SortError::io(path))),
+        }
+    }
+
+    /// Merges the runs pairwise until at most `count` of them are left,
+    /// the pairs of a round being merged in parallel while the pool has available workers
+    fn merge_rounds<T: ram::Sort + Channel, E: Encoding<T>>(mut runs: Vec<FileData>, count: usize, context: &Context<E>, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<Vec<FileData>, SortError> {
+        while runs.len() > count {
+            let mut merged = Vec::with_capacity(runs.len() / 2 + 1);
+            let mut pending = Vec::new();
+            let mut failure = None;
+            let mut runs_iter = runs.into_iter();
+            while let Some(left) = runs_iter.next() {
+                let right = match runs_iter.next() {
+                    Some(right) => right,
+                    None => {
+                        merged.push(left);
+                        break;
+                    }
+                };
+
+                // the merges already sent to the pool stop by themselves
+                if let Err(err) = context.cancel.check() {
+                    failure = Some(err);
+                    break;
+                }
+                let tmp_output_path = left.name.clone() + "m";
+                let pool = pool.lock().unwrap();
+                if pool.is_available() {
+                    let new_context = context.clone();
+                    let result = Arc::new(Mutex::new(None));
+                    let result_copy = Arc::clone(&result);
+                    let task = pool.execute(move || {
+                        let _ = (*result_copy.lock().unwrap()).insert(merge::<T, E>(left, right, tmp_output_path, &new_context));
+                    });
+                    pending.push((task, result));
+                } else {
+                    drop(pool);
+                    match merge::<T, E>(left, right, tmp_output_path, context) {
+                        Ok(result) => merged.push(result),
+                        Err(err) => {
+                            failure = Some(err);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            // the merges already sent to the pool are waited for before an error is returned
+            for (task, result) in pending {
+                task.recv().unwrap();
+                match Arc::into_inner(result).unwrap().into_inner().unwrap().unwrap_or(Err(SortError::Cancelled)) {
+                    Ok(result) => merged.push(result),
+                    Err(err) => {
+                        failure.get_or_insert(err);
+                    }
+                }
+            }
+            if let Some(err) = failure {
+                return Err(err);
+            }
+            runs = merged;
+        }
+        Ok(runs)
+    }
+
+    /// Cuts the values of a stream into runs as they arrive, whatever the policy, since the size of the stream isn't known
+    fn form_stream_runs<T: Sort + Channel, R: BufRead>(mut input: R, input_name: &str, tmp_dir: &str, sorter: &ExternalSorter, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<(Vec<FileData>, Context<TextEncoding>), SortError> {
+        let encoding = TextEncoding { options: sorter.parse_options.clone() };
+        let mut sample = Vec::new();
+        let mut line = Vec::new();
+        while sample.len() < SAMPLE_SIZE as usize && Encoding::<T>::copy_next(&encoding, &mut input, &mut sample, &mut line).map_err(SortError::io(input_name))? {}
+        let quota = Arc::new(TempQuota::new(sorter.max_temp_bytes));
+        let mut context = Context::from_sample::<T>(encoding, sorter.io_options, &sample, input_name, sorter.max_size_in_ram, quota)?;
+        context.set_low_disk(sorter.low_disk);
+        context.cancel = sorter.cancel.clone();
+        context.progress = sorter.progress.clone();
+        // the size of the stream and so its merge levels are unknown
+        context.progress.start(None, 0);
+
+        // the sampled values are sorted with the rest of the stream
+        let input = io::Cursor::new(sample).chain(input);
+        let runs = form_runs::<T, TextEncoding, _>(input, input_name, None, &unique_path(tmp_dir, "stream"), sorter.max_size_in_ram, &context, pool)?;
+        Ok((runs, context))
+    }
+
+    /// Sorts the values of a stream to a temporary file
+    fn sort_stream_prepared<T: Sort + Channel, R: BufRead>(input: R, input_name: &str, tmp_dir: &str, sorter: &ExternalSorter) -> Result<FileData, SortError> {
+        let pool = Arc::new(Mutex::new(ThreadPool::cancellable(usize::max(1, sorter.threads_count), sorter.cancel.clone())));
+        let (runs, context) = form_stream_runs::<T, R>(input, input_name, tmp_dir, sorter, Arc::clone(&pool))?;
+        let result = merge_runs::<T, TextEncoding>(runs, unique_path(tmp_dir, "stream_output"), &context, Arc::clone(&pool));
+        add_threads(&context.progress, &pool);
+        result
+    }
+
+    /// Sorts the values of a stream to another one. The last merge writes to the output instead of a temporary file.
+    fn sort_stream_to<T: Sort + Channel, R: BufRead, W: Write>(input: R, input_name: &str, output: W, output_name: &str, tmp_dir: &str, sorter: &ExternalSorter) -> Result<(), SortError> {
+        let pool = Arc::new(Mutex::new(ThreadPool::cancellable(usize::max(1, sorter.threads_count), sorter.cancel.clone())));
+        let (runs, context) = form_stream_runs::<T, R>(input, input_name, tmp_dir, sorter, Arc::clone(&pool))?;
+        let mut runs = merge_rounds::<T, TextEncoding>(runs, 2, &context, Arc::clone(&pool))?;
+        add_threads(&context.progress, &pool);
+        context.progress.begin(Phase::Write);
+
+        let _reservation = context.budget.reserve(MERGE_STREAMS * context.io_options.stream_buffers_size());
+        let mut output = BufWriter::with_capacity(context.io_options.block_size, output);
+        match (runs.pop(), runs.pop()) {
+            (Some(right), Some(left)) => merge_to::<T, TextEncoding, _>(&left, &right, &mut output, output_name, Phase::Write, None, &context)?,
+            (Some(run), None) => {
+                copy_stream(&mut context.reader(&run)?, &mut output, run.path(), output_name)?;
+                context.progress.add(Phase::Write, run.size(), context.progress.values_read());
+            }
+            _ => {}
+        }
+        output.flush().map_err(SortError::io(output_name))?;
+        context.progress.finish();
+        Ok(())
+    }
+
+    fn compute_in_ram_par<T: ram::Sort + Channel, E: Encoding<T>>(input: FileData, context: &Context<E>, pool: Arc<Mutex<ThreadPool<()>>>) -> Result<FileData, SortError> {
+        context.cancel.check()?;
+        context.progress.begin(Phase::Read);
+        let encoding = &context.encoding;
+        let threads = pool.lock().unwrap().size();
+        let _reservation = context.budget.reserve(context.memory.in_ram_size(input.size(), threads) as usize);
+        let data = encoding.load_par(input.path(), input.range.clone(), &pool, &context.cancel)?;
+        let (output_path, size, values) = (input.name.clone() + "w", input.size(), data.len() as u64);
+        context.progress.add(Phase::Read, size, values);
+        context.progress.begin(Phase::Sort);
+        // the input is only kept in the low disk mode, to free the blocks of its values once they are in the run
+        let consumed = context.low_disk.then_some(input);
+        let sorted = ram::merge_sort_par_helper_from_pool(&data, Arc::clone(&pool), &context.cancel)?;
+        drop(data);
+        context.cancel.check()?;
+        let output = FileData::temporary(output_path, size, &context.quota, |path| encoding.store_par(path, sorted, &pool, &context.cancel))?;
+        if let Some(input) = consumed {
+            input.release();
+        }
+        context.progress.add(Phase::Sort, output.size(), values);
+        context.progress.add_file(Phase::Sort);
+        Ok(output)
+    }
+
+    fn compute_in_ram_seq<T: ram::Sort, E: Encoding<T>>(input: FileData, context: &Context<E>) -> Result<FileData, SortError> {
+        context.cancel.check()?;
+        context.progress.begin(Phase::Read);
+        let encoding = &context.encoding;
+        let _reservation = context.budget.reserve(context.memory.in_ram_size(input.size(), 1) as usize);
+        let data = encoding.load(input.path(), input.range.clone())?;
+        let (output_path, size, values) = (input.name.clone() + "w", input.size(), data.len() as u64);
+        context.progress.add(Phase::Read, size, values);
+        context.progress.begin(Phase::Sort);
+        // the input is only kept in the low disk mode, to free the blocks of its values once they are in the run
+        let consumed = context.low_disk.then_some(input);
+        let sorted = ram::merge_sort_seq_cancellable(&data, &context.cancel)?;
+        drop(data);
+        context.cancel.check()?;
+        let output = FileData::temporary(output_path, size, &context.quota, |path| encoding.store(path, &sorted))?;
+        if let Some(input) = consumed {
+            input.release();
+        }
+        context.progress.add(Phase::Sort, output.size(), values);
+        context.progress.add_file(Phase::Sort);
+        Ok(output)
+    }
+
+    /// Splits the values into two byte ranges of the same file, near the middle of the values when possible.
+    /// Nothing is copied, `None` is returned when the range holds a single value.
+    fn split_file<T, E: Encoding<T>>(input: &FileData, context: &Context<E>) -> Result<Option<(FileData, FileData)>, SortError> {
+        let mut reader = BufReader::new(input.open()?);
+        let range = &input.range;
+        let mut split_point = |position| {
+            context.encoding.split_point(&mut reader, range, position).map_err(SortError::io(input.path()))
+        };
+
+        let mut middle = split_point(range.start + input.size() / 2)?;
+        if middle >= range.end {
+            // the value holding the middle lasts until the end, so the first value is split off instead
+            middle = split_point(range.start + 1)?;
+        }
+        if middle <= range.start || middle >= range.end {
+            return Ok(None);
+        }
+        Ok(Some((input.part(range.start..middle, "1"), input.part(middle..range.end, "2"))))
+    }
+
+    fn merge<T: ram::Sort, E: Encoding<T>>(left: FileData, right: FileData, output_path: String, context: &Context<E>) -> Result<FileData, SortError> {
+        context.cancel.check()?;
+        let _reservation = context.budget.reserve(MERGE_STREAMS * context.io_options.stream_buffers_size());
+        // in the low disk mode the output is charged as it is written, while the blocks of the inputs are given back as they are freed
+        let size = if context.low_disk { 0 } else { left.size() + right.size() };
+        let level = u32::max(left.level, right.level) + 1;
+        context.progress.begin(Phase::Merge(level));
+        let mut output = FileData::temporary(output_path, size, &context.quota, |path| write_merged::<T, E>(&left, &right, path, Phase::Merge(level), context))?;
+        context.progress.add_file(Phase::Merge(level));
+        output.level = level;
+        Ok(output)
+    }
+
+    fn write_merged<T: ram::Sort, E: Encoding<T>>(left: &FileData, right: &FileData, output_path: &str, phase: Phase, context: &Context<E>) -> Result<(), SortError> {
+        // the merged values are copied as they are, except for a missing new line at the end of a text file
+        let size = left.size() + right.size();
+        let output = File::create(output_path).map_err(SortError::io(output_path))?;
+        let mut output_buff = WriteBehind::new(output, &context.io_options, Some(size));
+        let quota = if context.low_disk { Some(context.quota.as_ref()) } else { None };
+        merge_to::<T, E, _>(left, right, &mut output_buff, output_path, phase, quota, context)?;
+        output_buff.finish().map_err(SortError::io(output_path))
+    }
+
+    /// Merges the values to the output, counting them as progress of `phase`.
+    /// The bytes merged are taken from `quota` as they are written, when given, until the merge ends.
+    fn merge_to<T: ram::Sort, E: Encoding<T>, W: Write>(left: &FileData, right: &FileData, output_buff: &mut W, output_name: &str, phase: Phase, quota: Option<&TempQuota>, context: &Context<E>) -> Result<(), SortError> {
+        let encoding = &context.encoding;
+        let output_buff = &mut CountingWriter { output: output_buff, bytes: 0 };
+        let mut charge = quota.map(TempQuota::charge);
+        let mut reported_bytes = 0;
+        let left_error = SortError::io(left.path());
+        let right_error = SortError::io(right.path());
+        let output_error = SortError::io(output_name);
+        let mut left_buff = context.reader(left)?;
+        let mut right_buff = context.reader(right)?;
+
+        let mut left_line = Vec::new();
+        let mut right_line = Vec::new();
+
+        let mut left_el = encoding.read_next(&mut left_buff, &mut left_line).map_err(&left_error)?;
+        let mut right_el = encoding.read_next(&mut right_buff, &mut right_line).map_err(&right_error)?;
+
+        let mut merged = 0;
+        loop {
+            if merged % MERGE_CHECK_INTERVAL == 0 && merged > 0 {
+                context.cancel.check()?;
+                if let Some(charge) = charge.as_mut() {
+                    charge.take(output_buff.bytes - reported_bytes)?;
+                }
+                context.progress.add(phase, output_buff.bytes - reported_bytes, MERGE_CHECK_INTERVAL as u64);
+                reported_bytes = output_buff.bytes;
+            }
+            merged += 1;
+            match (&left_el, &right_el) {
+                (Some(left), Some(right)) => {
+                    if left < right {
+                        encoding.write_next(output_buff, left).map_err(&output_error)?;
+                        left_el = encoding.read_next(&mut left_buff, &mut left_line).map_err(&left_error)?;
+                    } else {
+                        encoding.write_next(output_buff, right).map_err(&output_error)?;
+                        right_el = encoding.read_next(&mut right_buff, &mut right_line).map_err(&right_error)?;
+                    }
+                }
+                (Some(left), None) => {
+                    encoding.write_next(output_buff, left).map_err(&output_error)?;
+                    break;
+                }
+                (None, Some(right)) => {
+                    encoding.write_next(output_buff, right).map_err(&output_error)?;
+                    break;
+                }
+                (None, None) => {
+                    merged -= 1;
+                    break;
+                }
+            }
+        }
+
+        let copied = write_whole_to::<T, E, _, _>(&mut left_buff, output_buff, encoding).map_err(&output_error)?
+            + write_whole_to::<T, E, _, _>(&mut right_buff, output_buff, encoding).map_err(&output_error)?;
+        if let Some(charge) = charge.as_mut() {
+            charge.take(output_buff.bytes - reported_bytes)?;
+        }
+        context.progress.add(phase, output_buff.bytes - reported_bytes, (merged % MERGE_CHECK_INTERVAL + copied) as u64);
+        Ok(())
+    }
+
+    /// Copies the values left, returning their count
+    fn write_whole_to<T, E: Encoding<T>, R: BufRead, W: Write>(input: &mut R, output: &mut W, encoding: &E) -> io::Result<usize> {
+        let mut line = Vec::new();
+        let mut copied = 0;
+        while encoding.copy_next(input, output, &mut line)? {
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    /// Writer counting the bytes written through it
+    struct CountingWriter<'a, W> {
+        output: &'a mut W,
+        bytes: u64,
+    }
+
+    impl<W: Write> Write for CountingWriter<'_, W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let written = self.output.write(buf)?;
+            self.bytes += written as u64;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.output.flush()
+        }
+    }
+
+    /// Creates the temporary directory of the sort in the one given to the sorter, returning its path
+    fn create_tmp_dir(sorter: &ExternalSorter) -> Result<String, SortError> {
+        let tmp_dir = Path::new(&sorter.temp_dir).join(TMP_DIR).to_string_lossy().into_owned();
+        match fs::create_dir(&tmp_dir) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => Err(SortError::io(&tmp_dir)(err)),
+            _ => Ok(tmp_dir),
+        }
+    }
+
+    /// The directory is only removed once empty, other sorts may still use it
+    fn remove_tmp_dir(tmp_dir: &str) {
+        let _ = fs::remove_dir(tmp_dir);
+    }
+
+    /// Path of a temporary file that no other sort of any process uses
+    fn unique_path(tmp_dir: &str, name: &str) -> String {
+        let input_id = INPUTS_COUNT.fetch_add(1, Ordering::Relaxed);
+        format!("{}{}{}_{}_{}", tmp_dir, MAIN_SEPARATOR_STR, name, process::id(), input_id)
+    }
+
+    /// Copies all the bytes, telling the errors of the input from the ones of the output
+    fn copy_stream<R: Read, W: Write>(input: &mut R, output: &mut W, input_name: &str, output_name: &str) -> Result<(), SortError> {
+        let mut buffer = vec![0; 1 << 16];
+        loop {
+            let read = match input.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(SortError::io(input_name)(err)),
+            };
+            output.write_all(&buffer[..read]).map_err(SortError::io(output_name))?;
+        }
+        output.flush().map_err(SortError::io(output_name))
+    }
+
+    /// The input is sorted where it is, only the temporary files are written to the temporary directory
+    fn prepare_input(input: &str, tmp_dir: &str) -> Result<FileData, SortError> {
+        let size = fs::metadata(input).map_err(SortError::io(input))?.len();
+        let file_name = Path::new(input).file_name().map_or_else(|| "input".into(), |name| name.to_string_lossy());
+        Ok(FileData {
+            source: Arc::new(SourceFile { path: input.to_string(), temporary: false, charge: None, holes: None }),
+            range: 0..size,
+            name: unique_path(tmp_dir, &file_name),
+            level: 0,
+        })
+    }
+
+    /// Publishes the sorted values to the output. The output is left untouched when the sort failed or was cancelled.
+    fn clean(result: Result<FileData, SortError>, output_path: &str, tmp_dir: &str, sorter: &ExternalSorter) -> Result<(), SortError> {
+        // the result is removed when it is dropped if it couldn't be moved
+        let published = result.and_then(|result| {
+            sorter.cancel.check()?;
+            sorter.progress.begin(Phase::Write);
+            publish(result.path(), output_path)?;
+            sorter.progress.add(Phase::Write, result.size(), sorter.progress.values_read());
+            sorter.progress.finish();
+            Ok(())
+        });
+        remove_tmp_dir(tmp_dir);
+        published
+    }
+
+    /// Replaces the output at once with the sorted values, which are first moved next to it, or copied when
+    /// the temporary directory is on another file system, and synced to the disk.
+    /// An existing output keeps its permissions.
+    fn publish(result_path: &str, output_path: &str) -> Result<(), SortError> {
+        let output = Path::new(output_path);
+        let dir = parent_dir(output);
+        let file_name = output.file_name().map_or_else(|| "output".into(), |name| name.to_string_lossy());
+        let sibling = dir.join(format!(".{}.{}.tmp", file_name, process::id()));
+
+        let published = match fs::rename(result_path, &sibling) {
+            Err(err) if err.kind() == io::ErrorKind::CrossesDevices => fs::copy(result_path, &sibling).map(drop),
+            moved => moved,
+        }
+            .and_then(|()| match fs::metadata(output) {
+                Ok(metadata) => fs::set_permissions(&sibling, metadata.permissions()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(err) => Err(err),
+            })
+            .and_then(|()| File::open(&sibling)?.sync_all())
+            .and_then(|()| fs::rename(&sibling, output));
+        if published.is_err() {
+            let _ = fs::remove_file(&sibling);
+        }
+        published.and_then(|()| sync_dir(dir)).map_err(SortError::io(output_path))
+    }
+
+    fn parent_dir(path: &Path) -> &Path {
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    /// Fails before sorting the input when the temporary files don't fit in the quota or the free space
+    /// of their file system, or when the output doesn't fit in the free space of its own.
+    /// Every merge keeps its inputs until its output is written, so the last one needs twice the size
+    /// of the input whatever the policy. A sort in RAM only writes its output.
+    /// The low disk mode is taken when the sort fits only with it, whether it is returned.
+    /// It only saves space where the file system of the temporary files can punch holes, and consumes
+    /// the input then when it can.
+    fn check_space(input: &mut FileData, in_ram: bool, low_disk: bool, tmp_dir: &str, output: Option<&str>, quota: &TempQuota) -> Result<bool, SortError> {
+        let size = input.size();
+        if in_ram {
+            return check_space_for(size, size, size, tmp_dir, output, quota).map(|()| low_disk);
+        }
+        if !low_disk && check_space_for(size, 2 * size, 2 * size, tmp_dir, output, quota).is_ok() {
+            return Ok(false);
+        }
+        if !disk::can_punch_holes(tmp_dir) {
+            return check_space_for(size, 2 * size, 2 * size, tmp_dir, output, quota).map(|()| low_disk);
+        }
+        // the merges free their inputs as they read them, only the blocks being merged are kept twice
+        let temp_needed = size + size / 10;
+        // the runs take the blocks the input frees on the same file system
+        let input_dir = parent_dir(Path::new(input.path())).to_string_lossy().into_owned();
+        let free_needed = match input.consume() && matches!(disk::same_file_system(&input_dir, tmp_dir), Ok(true)) {
+            true => size / 10,
+            false => temp_needed,
+        };
+        check_space_for(size, temp_needed, free_needed, tmp_dir, output, quota).map(|()| true)
+    }
+
+    /// Checks that `temp_needed` bytes fit in the quota and `free_needed` in the free space of the temporary
+    /// directory, which the temporary files take beyond the input
+    fn check_space_for(size: u64, temp_needed: u64, free_needed: u64, tmp_dir: &str, output: Option<&str>, quota: &TempQuota) -> Result<(), SortError> {
+        quota.check(temp_needed)?;
+
+        let mut needed = vec![(tmp_dir.to_string(), free_needed)];
+        if let Some(output) = output {
+            let output_dir = parent_dir(Path::new(output)).to_string_lossy().into_owned();
+            // the sorted values are renamed to the output on the same file system and copied to another one
+            if let Ok(false) = disk::same_file_system(tmp_dir, &output_dir) {
+                needed.push((output_dir, size));
+            }
+        }
+        for (path, needed) in needed {
+            // the check is skipped where the free space can't be known
+            if let Ok(available) = disk::free_space(&path) {
+                if needed > available {
+                    return Err(SortError::NotEnoughSpace { path, needed, available });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the renaming of a file in the directory durable
+    fn sync_dir(dir: &Path) -> io::Result<()> {
+        match File::open(dir).and_then(|dir| dir.sync_all()) {
+            // directories can't be opened or synced on every platform and file system
+            Err(err) if matches!(err.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported) => Ok(()),
+            synced => synced,
+        }
+    }
+
+    /// Whether both paths name the same existing file, through links too
+    #[cfg(unix)]
+    fn is_same_file(first: &str, second: &str) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        match (fs::metadata(first), fs::metadata(second)) {
+            (Ok(first), Ok(second)) => first.dev() == second.dev() && first.ino() == second.ino(),
+            _ => false,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn is_same_file(first: &str, second: &str) -> bool {
+        match (fs::canonicalize(first), fs::canonicalize(second)) {
+            (Ok(first), Ok(second)) => first == second,
+            _ => false,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::element::ParseOptions;
+        use crate::float::{NanPolicy, TotalFloat};
+        use crate::memory::counting_allocator;
+        use crate::record::Record;
+        use serde::Deserialize;
+        use std::path::PathBuf;
+
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+        struct Event {
+            user: String,
+            timestamp: u64,
+        }
+
+        #[test]
+        fn test_merge_sort_serde() {
+            let events: Vec<Event> = (0..2000_u64)
+                .map(|i| Event { user: format!("user{}", i * 7919 % 13), timestamp: i * 104729 % 2000 })
+                .collect();
+            let mut expected = events.clone();
+            expected.sort();
+
+            let dir = std::env::temp_dir().join(format!("merge_sort_serde_{}", process::id()));
+            fs::create_dir(&dir).unwrap();
+            let sorter = ExternalSorter::new().memory(4096).threads(4).temp_dir(dir.to_str().unwrap());
+            let sorted: Vec<Event> = sorter.sort_iter(events).unwrap().collect::<Result<_, _>>().unwrap();
+            assert_eq!(sorted, expected);
+            // the runs are removed once the sorted values have been read
+            fs::remove_dir(&dir).unwrap();
+        }
+
+        #[test]
+        fn test_merge_sort_pipelined() {
+            let values: Vec<i64> = (0..20_000).map(|i| i * 7919 % 20_011 - 10_000).collect();
+            let mut expected = values.clone();
+            expected.sort();
+
+            let dir = std::env::temp_dir().join(format!("merge_sort_pipelined_{}", process::id()));
+            fs::create_dir(&dir).unwrap();
+            let sorter = ExternalSorter::new().memory(16_384).threads(3).policy(ExecPolicy::Pipelined).temp_dir(dir.to_str().unwrap());
+            let sorted: Vec<i64> = sorter.sort_iter(values).unwrap().collect::<Result<_, _>>().unwrap();
+            assert_eq!(sorted, expected);
+            fs::remove_dir(&dir).unwrap();
+        }
+
+        #[test]
+        fn test_split_file_at_lines() {
+            let path = std::env::temp_dir().join(format!("split_file_{}", process::id())).to_string_lossy().into_owned();
+            fs::write(&path, "123456\n7\n89\n").unwrap();
+            let input = FileData { source: Arc::new(SourceFile { path: path.clone(), temporary: true, charge: None, holes: None }), range: 0..12, name: path.clone(), level: 0 };
+            let context = Context::new::<u32>(TextEncoding { options: ParseOptions::default() }, IoOptions::default(), &input, 1 << 20, Arc::new(TempQuota::new(u64::MAX))).unwrap();
+
+            let (left, right) = split_file::<u32, _>(&input, &context).unwrap().unwrap();
+            assert_eq!((left.range.clone(), right.range.clone()), (0..7, 7..12));
+            assert!(split_file::<u32, _>(&left, &context).unwrap().is_none());
+            let (right_left, right_right) = split_file::<u32, _>(&right, &context).unwrap().unwrap();
+            assert_eq!((right_left.range.clone(), right_right.range.clone()), (7..9, 9..12));
+            assert!(split_file::<u32, _>(&right_left, &context).unwrap().is_none());
+
+            // the file is removed once no part of it is left
+            drop((input, left, right, right_left, right_right));
+            assert!(!Path::new(&path).exists());
+        }
+
+        #[test]
+        fn test_failed_sort_keeps_output() {
+            let dir = std::env::temp_dir().join(format!("rejected_{}", process::id()));
+            fs::create_dir(&dir).unwrap();
+            let temp_dir = dir.to_string_lossy().into_owned();
+            let input = dir.join("input").to_string_lossy().into_owned();
+            let output = dir.join("output").to_string_lossy().into_owned();
+            fs::write(&input, "2.5\nNaN\n1\n").unwrap();
+            fs::write(&output, "previous\n").unwrap();
+            let options = ParseOptions { nan_policy: NanPolicy::Reject, ..ParseOptions::default() };
+
+            let result = ExternalSorter::new().memory(1 << 20).threads(2).temp_dir(&temp_dir).parse_options(options).sort_file::<TotalFloat<f64>>(&input, &output);
+            assert!(matches!(result, Err(SortError::Parse { .. })));
+            assert_eq!(fs::read_to_string(&output).unwrap(), "previous\n");
+
+            let missing = dir.join("missing_input").to_string_lossy().into_owned();
+            let result = ExternalSorter::new().threads(1).temp_dir(&temp_dir).sort_file::<u32>(&missing, &output);
+            assert!(matches!(result, Err(SortError::Io { .. })));
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        /// Directory of its own for the test `name`, holding an input of 20000 values in reverse order
+        struct SortDir {
+            dir: PathBuf,
+            temp_dir: String,
+            input: String,
+            output: String,
+            values: String,
+        }
+
+        fn sort_dir(name: &str) -> SortDir {
+            let dir = std::env::temp_dir().join(format!("{}_{}", name, process::id()));
+            fs::create_dir(&dir).unwrap();
+            let temp_dir = dir.to_string_lossy().into_owned();
+            let input = dir.join("input").to_string_lossy().into_owned();
+            let output = dir.join("output").to_string_lossy().into_owned();
+            let values: String = (0..20000_u32).rev().map(|i| format!("{}\n", i)).collect();
+            fs::write(&input, &values).unwrap();
+            SortDir { dir, temp_dir, input, output, values }
+        }
+
+        #[test]
+        fn test_temp_quota() {
+            let SortDir { dir, temp_dir, input, output, values } = sort_dir("temp_quota");
+            fs::write(&output, "previous\n").unwrap();
+            let sorter = ExternalSorter::new().memory(100_000).threads(4).temp_dir(&temp_dir);
+
+            // the merges of the file are estimated beforehand to need 1.1 times its size at least, freeing their inputs
+            let size = values.len() as u64;
+            let result = sorter.clone().max_temp_bytes(size).sort_file::<u32>(&input, &output);
+            assert!(matches!(result, Err(SortError::TempQuota { needed, limit }) if needed == size + size / 10 && limit == size));
+            // the size of a stream is unknown, so its runs are stopped while they are written
+            let result = sorter.clone().max_temp_bytes(size / 2).sort_stream::<u32, _, _>(values.as_bytes(), Vec::new());
+            assert!(matches!(result, Err(SortError::TempQuota { .. })));
+            assert_eq!(fs::read_to_string(&output).unwrap(), "previous\n");
+
+            sorter.max_temp_bytes(2 * size).sort_file::<u32>(&input, &output).unwrap();
+            assert_eq!(fs::read_to_string(&output).unwrap().lines().map(|line| line.parse().unwrap()).collect::<Vec<u32>>(), (0..20000).collect::<Vec<u32>>());
+            // only the input and the output are left
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_low_disk() {
+            let SortDir { dir, temp_dir, input, output, values } = sort_dir("low_disk");
+
+            // the merges only fit in the quota where the blocks of the merged files are freed
+            let size = values.len() as u64;
+            let punched = disk::can_punch_holes(&temp_dir);
+            let quota = if punched { size + size / 10 } else { 2 * size };
+            for policy in [ExecPolicy::FullPar, ExecPolicy::FilePar, ExecPolicy::RamPar, ExecPolicy::Pipelined] {
+                for threads in [1, 4] {
+                    fs::write(&input, &values).unwrap();
+                    let sorter = ExternalSorter::new().memory(100_000).threads(threads).policy(policy).temp_dir(&temp_dir);
+                    sorter.low_disk(true).max_temp_bytes(quota).sort_file::<u32>(&input, &output).unwrap();
+                    let sorted: Vec<u32> = fs::read_to_string(&output).unwrap().lines().map(|line| line.parse().unwrap()).collect();
+                    assert_eq!(sorted, (0..20000).collect::<Vec<u32>>());
+                    // the input is consumed where its blocks can be freed, every value having gone to a run
+                    let consumed = fs::read(&input).unwrap();
+                    assert_eq!(consumed.len() as u64, size);
+                    if punched {
+                        assert!(consumed.iter().all(|&byte| byte == 0));
+                    } else {
+                        assert_eq!(consumed, values.as_bytes());
+                    }
+                }
+            }
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_cancelled_sort() {
+            let SortDir { dir, temp_dir, input, output, values } = sort_dir("cancelled");
+            fs::write(&output, "previous\n").unwrap();
+
+            let cancel = CancelToken::new();
+            cancel.cancel();
+            for policy in [ExecPolicy::FullPar, ExecPolicy::FilePar, ExecPolicy::RamPar, ExecPolicy::Pipelined] {
+                for memory in [100_000, 1 << 24] {
+                    let sorter = ExternalSorter::new().memory(memory).threads(4).policy(policy).temp_dir(&temp_dir).cancel_token(cancel.clone());
+                    assert!(matches!(sorter.sort_file::<u32>(&input, &output), Err(SortError::Cancelled)));
+                    assert!(matches!(sorter.sort_stream::<u32, _, _>(values.as_bytes(), Vec::new()), Err(SortError::Cancelled)));
+                }
+            }
+            assert_eq!(fs::read_to_string(&output).unwrap(), "previous\n");
+            // the temporary files are removed
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_progress_of_phases() {
+            let SortDir { dir, temp_dir, input, output, values } = sort_dir("progress");
+            let size = values.len() as u64;
+
+            for policy in [ExecPolicy::FullPar, ExecPolicy::Pipelined] {
+                let progress = Progress::new();
+                let sorter = ExternalSorter::new().memory(100_000).threads(4).policy(policy).temp_dir(&temp_dir).progress(progress.clone());
+                sorter.sort_file::<u32>(&input, &output).unwrap();
+
+                let snapshot = progress.snapshot();
+                assert!(snapshot.finished);
+                assert_eq!(snapshot.input_bytes, Some(size));
+                // every value is read, sorted, merged by the last level and written, the merges being unbalanced below it
+                let phases: Vec<(Phase, u64, u64)> = snapshot.phases.iter().map(|phase| (phase.phase, phase.bytes, phase.values)).collect();
+                assert_eq!(phases[..2], [(Phase::Read, size, 20000), (Phase::Sort, size, 20000)]);
+                assert_eq!(phases[phases.len() - 1], (Phase::Write, size, 20000));
+                assert!(matches!(phases[phases.len() - 2], (Phase::Merge(level), bytes, 20000) if level >= 2 && bytes == size));
+            }
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_sort_in_place() {
+            let dir = std::env::temp_dir().join(format!("in_place_{}", process::id()));
+            fs::create_dir(&dir).unwrap();
+            let path = dir.join("values").to_string_lossy().into_owned();
+            fs::write(&path, "3\n1\n2\n").unwrap();
+            let sorter = ExternalSorter::new().threads(2).temp_dir(&dir.to_string_lossy());
+
+            let result = sorter.clone().sort_file::<u32>(&path, &path);
+            assert!(matches!(result, Err(SortError::OutputIsInput { .. })));
+            assert_eq!(fs::read_to_string(&path).unwrap(), "3\n1\n2\n");
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+            }
+            sorter.in_place(true).sort_file::<u32>(&path, &path).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "1\n2\n3\n");
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+            }
+
+            // only the output is left in its directory
+            fs::remove_file(&path).unwrap();
+            fs::remove_dir(&dir).unwrap();
+        }
+
+        fn check_in_ram_size<T: Sort>(name: &str, lines: Vec<String>) {
+            let path = std::env::temp_dir().join(format!("{}_{}", name, process::id())).to_string_lossy().into_owned();
+            let quota = Arc::new(TempQuota::new(u64::MAX));
+            let input = FileData::temporary(path, 0, &quota, |path| fs::write(path, lines.join("\n")).map_err(SortError::io(path))).unwrap();
+            let encoding = TextEncoding { options: ParseOptions { keys: vec!["2".parse().unwrap()], ..ParseOptions::default() } };
+            let context = Context::new::<T>(encoding, IoOptions::default(), &input, usize::MAX, quota).unwrap();
+            let estimate = context.memory.in_ram_size(input.size(), 1) as usize;
+            // the progress of the phases is counted apart from the values
+            context.progress.begin(Phase::Read);
+            context.progress.begin(Phase::Sort);
+
+            let (result, peak) = counting_allocator::peak_heap(|| compute_in_ram_seq::<T, _>(input, &context).unwrap());
+            drop(result);
+            assert!(peak <= estimate && estimate <= 2 * peak, "{}: peak {}, estimate {}", name, peak, estimate);
+        }
+
+        #[test]
+        fn test_in_ram_size_bounds_peak_heap() {
+            let count = 20_000_u64;
+            check_in_ram_size::<u32>("in_ram_u32", (0..count).map(|i| (i * 7919 % count).to_string()).collect());
+            check_in_ram_size::<String>("in_ram_string", (0..count).map(|i| format!("value{}", i * 7919 % count)).collect());
+            check_in_ram_size::<Record>("in_ram_record", (0..count).map(|i| format!("{} user{} {}", i, i * 7919 % count, i % 7)).collect());
+        }
+    }
+}
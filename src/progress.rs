@@ -0,0 +1,377 @@
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Set by the handler of `handle_snapshot_signal` until `snapshot_requested` is called
+static SNAPSHOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Step of a sort whose bytes and values are counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Reading and parsing the parts of the input that fit in RAM
+    Read,
+    /// Sorting the parts in RAM and writing them to temporary files
+    Sort,
+    /// Merging sorted files, level 1 merging the sorted parts and every level merging the outputs of the previous one
+    Merge(u32),
+    /// Writing the sorted values to the output
+    Write,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Read => write!(f, "read"),
+            Phase::Sort => write!(f, "sort"),
+            Phase::Merge(level) => write!(f, "merge level {}", level),
+            Phase::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// Bytes and values processed by a phase
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseProgress {
+    pub phase: Phase,
+    pub bytes: u64,
+    pub values: u64,
+    /// Temporary files written: the sorted runs and the merge outputs
+    pub files: u64,
+    /// From the start of the first step of the phase to the latest progress, the phases overlapping
+    pub time: Duration,
+}
+
+/// State of a sort at some point
+#[derive(Debug, Clone)]
+pub struct ProgressSnapshot {
+    /// Phases that processed something, in their order
+    pub phases: Vec<PhaseProgress>,
+    /// Size of the input, unknown for streams
+    pub input_bytes: Option<u64>,
+    /// Merge levels the sort is estimated to need
+    pub merge_levels: u32,
+    pub elapsed: Duration,
+    /// Time since a phase processed something, which tells a slow sort from a stuck one
+    pub idle: Duration,
+    pub finished: bool,
+    /// Workers of the thread pool, 0 for a sequential sort
+    pub threads: usize,
+    /// Time the workers spent running jobs
+    pub threads_busy: Duration,
+}
+
+impl ProgressSnapshot {
+    /// Latest phase that processed something
+    pub fn phase(&self) -> Option<Phase> {
+        self.phases.last().map(|progress| progress.phase)
+    }
+
+    /// Bytes processed by all the phases
+    pub fn processed_bytes(&self) -> u64 {
+        self.phases.iter().map(|progress| progress.bytes).sum()
+    }
+
+    /// Bytes processed by all the phases once the sort is done: the input is read, sorted,
+    /// merged once per level and written
+    pub fn total_bytes(&self) -> Option<u64> {
+        let merge_levels = self.phases.iter()
+            .filter_map(|progress| match progress.phase {
+                Phase::Merge(level) => Some(level),
+                _ => None,
+            })
+            .fold(self.merge_levels, u32::max);
+        self.input_bytes.map(|bytes| bytes.saturating_mul(3 + u64::from(merge_levels)))
+    }
+
+    pub fn fraction(&self) -> Option<f64> {
+        if self.finished {
+            return Some(1.0);
+        }
+        self.total_bytes().map(|total| f64::min(1.0, self.processed_bytes() as f64 / u64::max(1, total) as f64))
+    }
+
+    /// Bytes processed per second by all the phases
+    pub fn throughput(&self) -> f64 {
+        self.processed_bytes() as f64 / f64::max(self.elapsed.as_secs_f64(), 1e-3)
+    }
+
+    /// Time left at the current throughput
+    pub fn eta(&self) -> Option<Duration> {
+        let left = self.total_bytes()?.saturating_sub(self.processed_bytes());
+        let throughput = self.throughput();
+        (throughput > 0.0).then(|| Duration::from_secs_f64(left as f64 / throughput))
+    }
+}
+
+impl fmt::Display for ProgressSnapshot {
+    /// One line like `merge level 2, 45.0% of 2.7G, 150.3M/s, 2m05s left`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.finished, self.phase()) {
+            (true, _) => write!(f, "done")?,
+            (false, Some(phase)) => write!(f, "{}", phase)?,
+            (false, None) => write!(f, "starting")?,
+        }
+        match (self.fraction(), self.input_bytes) {
+            (Some(fraction), Some(input_bytes)) => write!(f, ", {:.1}% of {}", fraction * 100.0, format_size(input_bytes))?,
+            _ => write!(f, ", {} processed", format_size(self.processed_bytes()))?,
+        }
+        write!(f, ", {}/s", format_size(self.throughput() as u64))?;
+        match self.eta() {
+            Some(eta) if !self.finished => write!(f, ", {} left", format_duration(eta)),
+            _ => write!(f, ", {} elapsed", format_duration(self.elapsed)),
+        }
+    }
+}
+
+/// Sizes like `512`, `1.5K` or `2.7G`, in powers of 1024 as `--max-size` takes them
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    let mut size = bytes as f64;
+    let mut unit = None;
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = Some(next);
+    }
+    match unit {
+        Some(unit) => format!("{:.1}{}", size, unit),
+        None => bytes.to_string(),
+    }
+}
+
+/// Durations like `42s`, `2m05s` or `6h12m`
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+type Callback = dyn Fn(&ProgressSnapshot) + Send + Sync;
+
+struct Reporting {
+    interval: Duration,
+    callback: Box<Callback>,
+    /// When the callback was last called
+    last: Mutex<Option<Instant>>,
+}
+
+struct PhaseState {
+    progress: PhaseProgress,
+    started: Instant,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    started: Option<Instant>,
+    updated: Option<Instant>,
+    phases: Vec<PhaseState>,
+    input_bytes: Option<u64>,
+    merge_levels: u32,
+    finished: bool,
+    threads: usize,
+    threads_busy: Duration,
+}
+
+impl State {
+    fn phase(&mut self, phase: Phase) -> &mut PhaseState {
+        let index = match self.phases.binary_search_by_key(&phase, |state| state.progress.phase) {
+            Ok(index) => index,
+            Err(index) => {
+                let now = Instant::now();
+                let progress = PhaseProgress { phase, bytes: 0, values: 0, files: 0, time: Duration::ZERO };
+                self.phases.insert(index, PhaseState { progress, started: now, updated: now });
+                index
+            }
+        };
+        &mut self.phases[index]
+    }
+}
+
+/// Counts the bytes and values processed by every phase of the sorts given this handle or one of its clones.
+/// A snapshot can be taken from any thread while they run.
+#[derive(Clone, Default)]
+pub struct Progress {
+    state: Arc<Mutex<State>>,
+    reporting: Option<Arc<Reporting>>,
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress").field("snapshot", &self.snapshot()).finish()
+    }
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress::default()
+    }
+
+    /// Calls `callback` with a snapshot at most once per `interval` while a sort makes progress,
+    /// and once more when it succeeds
+    pub fn with_callback(interval: Duration, callback: impl Fn(&ProgressSnapshot) + Send + Sync + 'static) -> Progress {
+        let reporting = Reporting { interval, callback: Box::new(callback), last: Mutex::new(None) };
+        Progress { state: Arc::default(), reporting: Some(Arc::new(reporting)) }
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let since = |instant: Option<Instant>| instant.map_or(Duration::ZERO, |instant| now.duration_since(instant));
+        ProgressSnapshot {
+            phases: state.phases.iter()
+                .map(|phase| PhaseProgress { time: phase.updated.duration_since(phase.started), ..phase.progress.clone() })
+                .collect(),
+            input_bytes: state.input_bytes,
+            merge_levels: state.merge_levels,
+            elapsed: since(state.started),
+            idle: since(state.updated.or(state.started)),
+            finished: state.finished,
+            threads: state.threads,
+            threads_busy: state.threads_busy,
+        }
+    }
+
+    /// Starts counting a sort of `input_bytes`, when they are known, estimated to need `merge_levels` levels
+    pub(crate) fn start(&self, input_bytes: Option<u64>, merge_levels: u32) {
+        *self.state.lock().unwrap() = State { started: Some(Instant::now()), input_bytes, merge_levels, ..State::default() };
+    }
+
+    /// Marks the start of a step of the phase, which is timed from its first step
+    pub(crate) fn begin(&self, phase: Phase) {
+        self.state.lock().unwrap().phase(phase);
+    }
+
+    pub(crate) fn add(&self, phase: Phase, bytes: u64, values: u64) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            state.updated = Some(now);
+            let phase = state.phase(phase);
+            phase.updated = now;
+            phase.progress.bytes += bytes;
+            phase.progress.values += values;
+        }
+        self.report(false);
+    }
+
+    /// Counts a temporary file written by the phase
+    pub(crate) fn add_file(&self, phase: Phase) {
+        self.state.lock().unwrap().phase(phase).progress.files += 1;
+    }
+
+    /// Counts the time the workers of a thread pool of the sort were busy
+    pub(crate) fn add_threads(&self, threads: usize, busy: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.threads = usize::max(state.threads, threads);
+        state.threads_busy += busy;
+    }
+
+    /// Values read so far, which is also the count of values written to the output
+    pub(crate) fn values_read(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.phases.iter().find(|phase| phase.progress.phase == Phase::Read).map_or(0, |phase| phase.progress.values)
+    }
+
+    pub(crate) fn finish(&self) {
+        self.state.lock().unwrap().finished = true;
+        self.report(true);
+    }
+
+    fn report(&self, force: bool) {
+        let Some(reporting) = &self.reporting else {
+            return;
+        };
+        // a thread already calling the callback is enough
+        let Ok(mut last) = reporting.last.try_lock() else {
+            return;
+        };
+        if force || last.is_none_or(|last| last.elapsed() >= reporting.interval) {
+            *last = Some(Instant::now());
+            (reporting.callback)(&self.snapshot());
+        }
+    }
+}
+
+/// Makes SIGUSR1 request a snapshot of the progress, for `snapshot_requested` to tell
+#[cfg(unix)]
+pub fn handle_snapshot_signal() -> io::Result<()> {
+    extern "C" fn handle_signal(_: libc::c_int) {
+        SNAPSHOT_REQUESTED.store(true, Ordering::SeqCst);
+    }
+
+    // SAFETY: the handler only stores to an atomic
+    if unsafe { libc::signal(libc::SIGUSR1, handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Signals are only handled on Unix
+#[cfg(not(unix))]
+pub fn handle_snapshot_signal() -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Whether a snapshot was requested since the last call
+pub fn snapshot_requested() -> bool {
+    SNAPSHOT_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let progress = Progress::new();
+        progress.start(Some(1000), 2);
+        progress.add(Phase::Sort, 1000, 10);
+        progress.add(Phase::Read, 1000, 10);
+        progress.add(Phase::Merge(1), 500, 5);
+
+        let snapshot = progress.snapshot();
+        let phases: Vec<Phase> = snapshot.phases.iter().map(|progress| progress.phase).collect();
+        assert_eq!(phases, [Phase::Read, Phase::Sort, Phase::Merge(1)]);
+        assert_eq!(snapshot.phase(), Some(Phase::Merge(1)));
+        // read, sorted, merged twice and written
+        assert_eq!(snapshot.total_bytes(), Some(5000));
+        assert_eq!(snapshot.fraction(), Some(0.5));
+        assert!(snapshot.eta().is_some());
+
+        // more levels than estimated make the sort longer
+        progress.add(Phase::Merge(3), 0, 0);
+        assert_eq!(progress.snapshot().total_bytes(), Some(6000));
+        assert_eq!(progress.values_read(), 10);
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let calls_copy = Arc::clone(&calls);
+        let progress = Progress::with_callback(Duration::from_secs(3600), move |snapshot| calls_copy.lock().unwrap().push(snapshot.finished));
+        progress.start(None, 0);
+        progress.add(Phase::Read, 10, 1);
+        progress.add(Phase::Read, 10, 1);
+        progress.finish();
+        // the first update and the end, the second update being too close to the first one
+        assert_eq!(*calls.lock().unwrap(), [false, true]);
+        assert_eq!(progress.snapshot().to_string().split(',').next(), Some("done"));
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_size(512), "512");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(3 << 30), "3.0G");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m05s");
+        assert_eq!(format_duration(Duration::from_secs(6 * 3600 + 720)), "6h12m");
+    }
+}
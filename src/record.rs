@@ -0,0 +1,340 @@
+use crate::element::{Element, Format, ParseError, ParseOptions, Random};
+use crate::float::TotalFloat;
+use rand::Rng;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KeyType {
+    Str,
+    Int,
+    Num,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum MissingPolicy {
+    First,
+    #[default]
+    Last,
+    Reject,
+}
+
+impl FromStr for MissingPolicy {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<MissingPolicy, Self::Err> {
+        match input {
+            "first" => Ok(MissingPolicy::First),
+            "last" => Ok(MissingPolicy::Last),
+            "reject" => Ok(MissingPolicy::Reject),
+            _ => Err("Wrong value"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Field {
+    /// Column of a record, numbered from 1
+    Column(usize),
+    /// Path of a JSON field, e.g. `.user.id`
+    Path(Vec<String>),
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Column(column) => write!(f, "{}", column),
+            Field::Path(path) => write!(f, ".{}", path.join(".")),
+        }
+    }
+}
+
+/// Sort key given as `field[,type][,direction]`, e.g. `2,num,desc` or `.user.id,str`.
+/// Type defaults to `str` and direction to `asc`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeySpec {
+    pub field: Field,
+    pub key_type: KeyType,
+    pub direction: Direction,
+}
+
+impl FromStr for KeySpec {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<KeySpec, Self::Err> {
+        let mut parts = input.split(',');
+        let field = match parts.next() {
+            Some(".") => Field::Path(Vec::new()),
+            Some(path) if path.starts_with('.') => Field::Path(path[1..].split('.').map(String::from).collect()),
+            Some(column) => match column.parse::<usize>() {
+                Ok(column) if column > 0 => Field::Column(column),
+                _ => return Err(format!("Wrong field in key: {}", input)),
+            },
+            None => return Err(format!("Wrong field in key: {}", input)),
+        };
+
+        let mut spec = KeySpec { field, key_type: KeyType::Str, direction: Direction::Asc };
+        for part in parts {
+            match part {
+                "str" => spec.key_type = KeyType::Str,
+                "int" => spec.key_type = KeyType::Int,
+                "num" => spec.key_type = KeyType::Num,
+                "asc" => spec.direction = Direction::Asc,
+                "desc" => spec.direction = Direction::Desc,
+                _ => return Err(format!("Wrong key option: {}", part)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// Key value taken from a record. Fields that are missing or cannot be parsed
+/// as the key type are placed at the start or at the end of the output,
+/// independently of the key direction.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub(crate) enum KeyValue {
+    MissingFirst,
+    Int(i128),
+    Num(TotalFloat<f64>),
+    Str(String),
+    MissingLast,
+}
+
+impl KeyValue {
+    pub(crate) fn missing(options: &ParseOptions) -> Result<KeyValue, ParseError> {
+        match options.missing_policy {
+            MissingPolicy::First => Ok(KeyValue::MissingFirst),
+            MissingPolicy::Last => Ok(KeyValue::MissingLast),
+            MissingPolicy::Reject => Err(ParseError::Rejected("Records with missing keys are rejected")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct Key {
+    pub(crate) value: KeyValue,
+    pub(crate) direction: Direction,
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (&self.value, &other.value) {
+            (KeyValue::MissingFirst | KeyValue::MissingLast, _) | (_, KeyValue::MissingFirst | KeyValue::MissingLast) => {
+                self.value.cmp(&other.value)
+            }
+            _ => match self.direction {
+                Direction::Asc => self.value.cmp(&other.value),
+                Direction::Desc => other.value.cmp(&self.value),
+            },
+        }
+    }
+}
+
+/// Single line of the input compared by its keys, lexicographically in the order they were given.
+/// Without any keys the whole line is compared as a string.
+#[derive(Debug, Clone)]
+pub struct Record {
+    line: String,
+    keys: Vec<Key>,
+}
+
+impl Record {
+    pub(crate) fn new(line: &str, keys: Vec<Key>) -> Record {
+        Record { line: line.to_string(), keys }
+    }
+
+    fn fields<'a>(line: &'a str, separator: Option<char>) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        match separator {
+            Some(separator) => Box::new(line.split(separator)),
+            None => Box::new(line.split_whitespace()),
+        }
+    }
+
+    pub(crate) fn key_value(field: Option<&str>, key_type: KeyType, options: &ParseOptions) -> Result<KeyValue, ParseError> {
+        let field = match field {
+            Some(field) => field.trim(),
+            None => return KeyValue::missing(options),
+        };
+        match key_type {
+            KeyType::Str => Ok(KeyValue::Str(field.to_string())),
+            KeyType::Int => match field.parse() {
+                Ok(value) => Ok(KeyValue::Int(value)),
+                Err(_) => KeyValue::missing(options),
+            },
+            KeyType::Num => match TotalFloat::<f64>::parse(field, options) {
+                Ok(value) => Ok(KeyValue::Num(value)),
+                Err(ParseError::Invalid) => KeyValue::missing(options),
+                Err(err) => Err(err),
+            },
+        }
+    }
+}
+
+impl PartialEq for Record {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Record {}
+
+impl PartialOrd for Record {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Record {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.keys.is_empty() {
+            return self.line.cmp(&other.line);
+        }
+        self.keys.cmp(&other.keys)
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.line)
+    }
+}
+
+impl Format for Record {
+    fn format_to<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        output.write_all(self.line.as_bytes())
+    }
+}
+
+impl Element for Record {
+    const NAME: &'static str = "record";
+    const WHOLE_LINE: bool = true;
+
+    fn check_options(options: &ParseOptions) -> Result<(), String> {
+        match options.keys.iter().find(|spec| matches!(spec.field, Field::Path(_))) {
+            Some(spec) => Err(format!("The key {} is a path of JSON fields, records are sorted by column numbers", spec.field)),
+            None => Ok(()),
+        }
+    }
+
+    fn parse(token: &str, options: &ParseOptions) -> Result<Self, ParseError> {
+        let mut keys = Vec::with_capacity(options.keys.len());
+        for spec in &options.keys {
+            let field = match spec.field {
+                Field::Column(column) => Record::fields(token, options.field_separator).nth(column - 1),
+                Field::Path(_) => None,
+            };
+            keys.push(Key { value: Record::key_value(field, spec.key_type, options)?, direction: spec.direction });
+        }
+        Ok(Record::new(token, keys))
+    }
+
+    fn heap_size(&self) -> usize {
+        let key_strings: usize = self.keys.iter().map(|key| match &key.value {
+            KeyValue::Str(value) => value.capacity(),
+            _ => 0,
+        }).sum();
+        self.line.capacity() + self.keys.capacity() * std::mem::size_of::<Key>() + key_strings
+    }
+}
+
+impl Random for Record {
+    fn random_vec(count: usize) -> Vec<Self> {
+        let mut rng = rand::thread_rng();
+        let names = String::random_vec(count);
+        names
+            .into_iter()
+            .map(|name| Record {
+                line: format!("{} {} {}", rng.gen::<u32>(), name, rng.gen_range(-1.0e6..1.0e6)),
+                keys: Vec::new(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sort_lines(lines: &[&str], keys: &[&str], field_separator: Option<char>) -> Vec<String> {
+        let options = ParseOptions {
+            keys: keys.iter().map(|key| key.parse().unwrap()).collect(),
+            field_separator,
+            ..ParseOptions::default()
+        };
+        let mut records: Vec<Record> = lines.iter().map(|line| Record::parse(line, &options).unwrap()).collect();
+        records.sort();
+        records.iter().map(|record| record.to_string()).collect()
+    }
+
+    #[test]
+    fn test_key_spec_from_str() {
+        assert_eq!("2,num,desc".parse(), Ok(KeySpec { field: Field::Column(2), key_type: KeyType::Num, direction: Direction::Desc }));
+        assert_eq!("1".parse(), Ok(KeySpec { field: Field::Column(1), key_type: KeyType::Str, direction: Direction::Asc }));
+        assert_eq!(
+            ".user.id,int".parse(),
+            Ok(KeySpec { field: Field::Path(vec!["user".to_string(), "id".to_string()]), key_type: KeyType::Int, direction: Direction::Asc })
+        );
+        assert!("0,str".parse::<KeySpec>().is_err());
+        assert!("1,text".parse::<KeySpec>().is_err());
+    }
+
+    #[test]
+    fn test_check_options() {
+        let options = |key: &str| ParseOptions { keys: vec!["1".parse().unwrap(), key.parse().unwrap()], ..ParseOptions::default() };
+        assert!(Record::check_options(&options("2,int")).is_ok());
+        assert_eq!(Record::check_options(&options(".a")), Err("The key .a is a path of JSON fields, records are sorted by column numbers".to_string()));
+    }
+
+    #[test]
+    fn test_multiple_keys() {
+        let lines = ["b 1.5", "a 2", "c 2", "a 10", "d"];
+        assert_eq!(sort_lines(&lines, &["2,num,desc", "1,str,asc"], None), vec!["a 10", "a 2", "c 2", "b 1.5", "d"]);
+    }
+
+    #[test]
+    fn test_missing_policy() {
+        let lines = ["b 1", "a", "c x", "d 0"];
+        assert_eq!(sort_lines(&lines, &["2,int,desc"], None), vec!["b 1", "d 0", "a", "c x"]);
+
+        let options = ParseOptions {
+            keys: vec!["2,int".parse().unwrap()],
+            missing_policy: MissingPolicy::First,
+            ..ParseOptions::default()
+        };
+        let mut records: Vec<Record> = lines.iter().map(|line| Record::parse(line, &options).unwrap()).collect();
+        records.sort();
+        assert_eq!(records.iter().map(|record| record.to_string()).collect::<Vec<_>>(), vec!["a", "c x", "d 0", "b 1"]);
+
+        let options = ParseOptions { missing_policy: MissingPolicy::Reject, ..options };
+        assert!(matches!(Record::parse("a", &options), Err(ParseError::Rejected(_))));
+    }
+
+    #[test]
+    fn test_composite_integer_keys() {
+        let lines = ["18446744073709551615,2", "3,18446744073709551614", "3,5"];
+        assert_eq!(
+            sort_lines(&lines, &["1,int", "2,int"], Some(',')),
+            vec!["3,5", "3,18446744073709551614", "18446744073709551615,2"]
+        );
+    }
+
+    #[test]
+    fn test_whole_line_without_keys() {
+        assert_eq!(sort_lines(&["b a", "a b", " c"], &[], None), vec![" c", "a b", "b a"]);
+    }
+}
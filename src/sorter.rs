@@ -0,0 +1,188 @@
+use std::io::{Read, Write};
+use std::thread::available_parallelism;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::block_io::IoOptions;
+use crate::cancel::CancelToken;
+use crate::element::ParseOptions;
+use crate::error::SortError;
+use crate::memory::{self, SystemMemory};
+use crate::merge_sorter::file::{self, ExecPolicy, Sort, SortedIter};
+use crate::merge_sorter::ram;
+use crate::progress::Progress;
+use crate::thread_pool::Channel;
+
+/// Sorts values that may not fit in RAM, spilling them to temporary files.
+///
+/// ```no_run
+/// use large_data_sorter::{ExecPolicy, ExternalSorter};
+///
+/// let sorter = ExternalSorter::new().memory(1 << 30).threads(8).policy(ExecPolicy::FullPar).temp_dir("/var/tmp");
+/// sorter.sort_file::<u64>("input.txt", "sorted.txt")?;
+/// # Ok::<(), large_data_sorter::SortError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ExternalSorter {
+    pub(crate) max_size_in_ram: usize,
+    pub(crate) threads_count: usize,
+    pub(crate) exec_policy: ExecPolicy,
+    pub(crate) parse_options: ParseOptions,
+    pub(crate) io_options: IoOptions,
+    pub(crate) temp_dir: String,
+    pub(crate) in_place: bool,
+    pub(crate) max_temp_bytes: u64,
+    pub(crate) low_disk: bool,
+    pub(crate) cancel: CancelToken,
+    pub(crate) progress: Progress,
+}
+
+impl Default for ExternalSorter {
+    /// Half of the available memory, all the cores, the `FullPar` policy and the current directory for the temporary files
+    fn default() -> Self {
+        ExternalSorter {
+            max_size_in_ram: memory::default_budget(&SystemMemory::default()).unwrap_or(memory::FALLBACK_BUDGET),
+            threads_count: available_parallelism().map_or(1, |threads| threads.get()),
+            exec_policy: ExecPolicy::FullPar,
+            parse_options: ParseOptions::default(),
+            io_options: IoOptions::default(),
+            temp_dir: String::from("."),
+            in_place: false,
+            max_temp_bytes: u64::MAX,
+            low_disk: false,
+            cancel: CancelToken::new(),
+            progress: Progress::new(),
+        }
+    }
+}
+
+impl ExternalSorter {
+    pub fn new() -> ExternalSorter {
+        ExternalSorter::default()
+    }
+
+    /// Maximum heap bytes taken at once by all threads for the values sorted in RAM and the merge buffers
+    pub fn memory(mut self, max_size_in_ram: usize) -> ExternalSorter {
+        self.max_size_in_ram = max_size_in_ram;
+        self
+    }
+
+    /// Maximum threads count, 1 sorts sequentially
+    pub fn threads(mut self, threads_count: usize) -> ExternalSorter {
+        self.threads_count = threads_count;
+        self
+    }
+
+    pub fn policy(mut self, exec_policy: ExecPolicy) -> ExternalSorter {
+        self.exec_policy = exec_policy;
+        self
+    }
+
+    /// Directory in which the temporary directory of the sort is created
+    pub fn temp_dir(mut self, temp_dir: &str) -> ExternalSorter {
+        self.temp_dir = temp_dir.to_string();
+        self
+    }
+
+    /// Maximum bytes of temporary files kept at once. The sort fails before starting when it is estimated
+    /// to need more, and stops before writing more otherwise.
+    pub fn max_temp_bytes(mut self, max_temp_bytes: u64) -> ExternalSorter {
+        self.max_temp_bytes = max_temp_bytes;
+        self
+    }
+
+    /// Frees the disk blocks of the temporary files as they are read, so a file takes about 1.1 times its size
+    /// of temporary files instead of twice. Where the file system can't punch holes, the blocks are only freed once
+    /// a whole file has been merged. Files are sorted this way anyway when they wouldn't fit otherwise.
+    /// The input of a file sort is consumed as well where it can be written to: the blocks of its values are freed
+    /// once they are in runs, so the sort needs about 1.1 times its size on the disk, with the input.
+    /// It reads as zeros there afterwards, and the values are lost when the sort fails or is cancelled then.
+    pub fn low_disk(mut self, low_disk: bool) -> ExternalSorter {
+        self.low_disk = low_disk;
+        self
+    }
+
+    /// Allows the output of `sort_file` to be its input, which is then replaced once all of it has been sorted
+    pub fn in_place(mut self, in_place: bool) -> ExternalSorter {
+        self.in_place = in_place;
+        self
+    }
+
+    /// Token stopping the sorts once it is cancelled, they then remove their temporary files and leave the output untouched
+    pub fn cancel_token(mut self, cancel: CancelToken) -> ExternalSorter {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Counts the bytes and values processed by every phase of the sorts, which can be read from the progress
+    /// or one of its clones while they run
+    pub fn progress(mut self, progress: Progress) -> ExternalSorter {
+        self.progress = progress;
+        self
+    }
+
+    /// Options of the text formats, like the keys of records
+    pub fn parse_options(mut self, parse_options: ParseOptions) -> ExternalSorter {
+        self.parse_options = parse_options;
+        self
+    }
+
+    /// Tuning of the reads and writes of merges
+    pub fn io_options(mut self, io_options: IoOptions) -> ExternalSorter {
+        self.io_options = io_options;
+        self
+    }
+
+    /// Sorts a text file with a value per line. The output is only replaced once the whole sort succeeded,
+    /// at once and after the sorted values have been synced to the disk.
+    /// `-` stands for the standard input or output, which are sorted like `sort_stream` does.
+    pub fn sort_file<T: Sort + Channel>(&self, input: &str, output: &str) -> Result<(), SortError> {
+        T::check_options(&self.parse_options).map_err(SortError::InvalidOptions)?;
+        file::merge_sort::<T>(input, output, self)
+    }
+
+    /// Sorts the lines read from `input` and writes them to `output`. Whatever the policy, the input is cut
+    /// into sorted runs as it arrives, since its size isn't known, and the last merge writes to `output`.
+    pub fn sort_stream<T: Sort + Channel, R: Read, W: Write>(&self, input: R, output: W) -> Result<(), SortError> {
+        T::check_options(&self.parse_options).map_err(SortError::InvalidOptions)?;
+        file::merge_sort_stream::<T, R, W>(input, output, self)
+    }
+
+    /// Sorts any values supported by `serde`, which are kept in temporary files in a compact binary encoding.
+    /// The parse options don't apply to them.
+    pub fn sort_iter<T, I>(&self, input: I) -> Result<SortedIter<T>, SortError>
+        where
+            T: ram::Sort + Channel + Serialize + DeserializeOwned,
+            I: IntoIterator<Item = T>,
+    {
+        file::merge_sort_serde(input, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Record;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_sort_stream() {
+        let temp_dir = std::env::temp_dir().join(format!("sort_stream_{}", process::id())).to_string_lossy().into_owned();
+        fs::create_dir(&temp_dir).unwrap();
+        let input: String = (0..5000_u64).map(|i| format!("{} user{}\n", i, i * 7919 % 5000)).collect();
+        let mut expected: Vec<&str> = input.lines().collect();
+        expected.sort_by_key(|line| line.split(' ').nth(1).unwrap());
+
+        let options = ParseOptions { keys: vec!["2".parse().unwrap()], ..ParseOptions::default() };
+        // a single run, two runs merged to the output and more runs merged beforehand
+        for memory in [1 << 24, 3_000_000, 50_000] {
+            let sorter = ExternalSorter::new().memory(memory).threads(4).temp_dir(&temp_dir).parse_options(options.clone());
+            let mut output = Vec::new();
+            sorter.sort_stream::<Record, _, _>(input.as_bytes(), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap().lines().collect::<Vec<_>>(), expected);
+        }
+
+        // the temporary directory is removed inside the given one
+        fs::remove_dir(&temp_dir).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+use std::fs;
+use std::path::PathBuf;
+use serde::Serialize;
+use crate::memory;
+use crate::progress::{Phase, ProgressSnapshot};
+
+/// Inputs merged at once by every merge
+pub const MERGE_FAN_IN: u32 = 2;
+
+/// Figures of a finished sort, to plan the capacity that sorts need
+#[derive(Debug, Clone, Serialize)]
+pub struct SortStats {
+    /// Size of the input, unknown for streams
+    pub input_bytes: Option<u64>,
+    pub values: u64,
+    /// Sorted runs written before merging, the parts of the input sorted in RAM
+    pub runs: u64,
+    /// Merge levels, the runs being merged pairwise level after level
+    pub merge_passes: u32,
+    pub merge_fan_in: u32,
+    pub merges: u64,
+    pub seconds: f64,
+    pub phases: Vec<PhaseStats>,
+    /// Bytes read and written by the whole process during the sort, `None` where they can't be read
+    pub process_io: Option<ProcessIo>,
+    /// Highest resident memory of the process
+    pub peak_rss_bytes: Option<u64>,
+    /// Workers of the thread pool, 0 for a sequential sort
+    pub threads: usize,
+    pub threads_busy_seconds: f64,
+    /// Share of the time the workers were busy, their waits for the jobs they started included
+    pub thread_utilisation: Option<f64>,
+}
+
+/// Bytes and values of a phase. The values of a phase are read from or written to the temporary files,
+/// apart from the output that is renamed or copied to its place.
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseStats {
+    /// `read`, `sort`, `merge` or `write`
+    pub phase: &'static str,
+    /// Level of a merge phase
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<u32>,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub values: u64,
+    /// Temporary files written
+    pub files: u64,
+    /// From the start of the first step of the phase to its end, the phases overlapping
+    pub seconds: f64,
+}
+
+impl SortStats {
+    pub fn new(snapshot: &ProgressSnapshot, process_io: Option<ProcessIo>, peak_rss_bytes: Option<u64>) -> SortStats {
+        let phases: Vec<PhaseStats> = snapshot.phases.iter()
+            .map(|progress| {
+                let (phase, level, bytes_read, bytes_written) = match progress.phase {
+                    Phase::Read => ("read", None, progress.bytes, 0),
+                    Phase::Sort => ("sort", None, 0, progress.bytes),
+                    Phase::Merge(level) => ("merge", Some(level), progress.bytes, progress.bytes),
+                    Phase::Write => ("write", None, 0, progress.bytes),
+                };
+                PhaseStats { phase, level, bytes_read, bytes_written, values: progress.values, files: progress.files, seconds: progress.time.as_secs_f64() }
+            })
+            .collect();
+        let files = |phase: &str| phases.iter().filter(|stats| stats.phase == phase).map(|stats| stats.files).sum();
+        let elapsed = snapshot.elapsed.as_secs_f64();
+        let busy = snapshot.threads_busy.as_secs_f64();
+
+        SortStats {
+            input_bytes: snapshot.input_bytes,
+            values: phases.iter().find(|stats| stats.phase == "read").map_or(0, |stats| stats.values),
+            runs: files("sort"),
+            merge_passes: phases.iter().filter_map(|stats| stats.level).max().unwrap_or(0),
+            merge_fan_in: MERGE_FAN_IN,
+            merges: files("merge"),
+            seconds: elapsed,
+            process_io,
+            peak_rss_bytes,
+            threads: snapshot.threads,
+            threads_busy_seconds: busy,
+            thread_utilisation: (snapshot.threads > 0 && elapsed > 0.0).then(|| busy / (snapshot.threads as f64 * elapsed)),
+            phases,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Bytes read and written by a process, as counted by Linux in `/proc/<pid>/io`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ProcessIo {
+    /// Bytes passed to reads, from the page cache or not
+    pub rchar: u64,
+    /// Bytes passed to writes
+    pub wchar: u64,
+    /// Bytes fetched from the storage
+    pub read_bytes: u64,
+    /// Bytes sent to the storage
+    pub write_bytes: u64,
+}
+
+impl ProcessIo {
+    fn parse(io: &str) -> Option<ProcessIo> {
+        let field = |name: &str| {
+            io.lines()
+                .find_map(|line| line.strip_prefix(name).and_then(|value| value.strip_prefix(':')))
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+        Some(ProcessIo { rchar: field("rchar")?, wchar: field("wchar")?, read_bytes: field("read_bytes")?, write_bytes: field("write_bytes")? })
+    }
+
+    /// Bytes counted since `before`
+    pub fn since(&self, before: &ProcessIo) -> ProcessIo {
+        ProcessIo {
+            rchar: self.rchar.saturating_sub(before.rchar),
+            wchar: self.wchar.saturating_sub(before.wchar),
+            read_bytes: self.read_bytes.saturating_sub(before.read_bytes),
+            write_bytes: self.write_bytes.saturating_sub(before.write_bytes),
+        }
+    }
+}
+
+/// Files describing the current process, under the `/proc` tree given to `new`
+#[derive(Debug, Clone)]
+pub struct ProcessStats {
+    proc_dir: PathBuf,
+}
+
+impl Default for ProcessStats {
+    fn default() -> Self {
+        ProcessStats::new("/proc")
+    }
+}
+
+impl ProcessStats {
+    pub fn new(proc_dir: impl Into<PathBuf>) -> ProcessStats {
+        ProcessStats { proc_dir: proc_dir.into() }
+    }
+
+    pub fn io(&self) -> Option<ProcessIo> {
+        ProcessIo::parse(&fs::read_to_string(self.proc_dir.join("self").join("io")).ok()?)
+    }
+
+    /// Highest resident memory of the process so far
+    pub fn peak_rss(&self) -> Option<u64> {
+        memory::proc_size(&self.proc_dir.join("self").join("status"), "VmHWM")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::PhaseProgress;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sort_stats() {
+        let phase = |phase, bytes, values, files| PhaseProgress { phase, bytes, values, files, time: Duration::from_secs(1) };
+        let snapshot = ProgressSnapshot {
+            phases: vec![
+                phase(Phase::Read, 1000, 100, 0),
+                phase(Phase::Sort, 1000, 100, 4),
+                phase(Phase::Merge(1), 1000, 100, 2),
+                phase(Phase::Merge(2), 1000, 100, 1),
+                phase(Phase::Write, 1000, 100, 0),
+            ],
+            input_bytes: Some(1000),
+            merge_levels: 2,
+            elapsed: Duration::from_secs(4),
+            idle: Duration::ZERO,
+            finished: true,
+            threads: 2,
+            threads_busy: Duration::from_secs(6),
+        };
+        let stats = SortStats::new(&snapshot, None, Some(1 << 20));
+        assert_eq!((stats.values, stats.runs, stats.merges, stats.merge_passes), (100, 4, 3, 2));
+        assert_eq!(stats.thread_utilisation, Some(0.75));
+        assert_eq!((stats.phases[2].bytes_read, stats.phases[2].bytes_written), (1000, 1000));
+
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+        assert_eq!(json["phases"][3]["phase"], "merge");
+        assert_eq!(json["phases"][3]["level"], 2);
+        assert!(json["phases"][0].get("level").is_none());
+        assert_eq!(json["peak_rss_bytes"], 1 << 20);
+    }
+
+    #[test]
+    fn test_process_stats() {
+        let root = std::env::temp_dir().join(format!("process_stats_{}", std::process::id()));
+        fs::create_dir_all(root.join("self")).unwrap();
+        fs::write(root.join("self").join("io"), "rchar: 5000\nwchar: 300\nsyscr: 9\nsyscw: 2\nread_bytes: 4096\nwrite_bytes: 0\ncancelled_write_bytes: 0\n").unwrap();
+        fs::write(root.join("self").join("status"), "Name:\tsorter\nVmPeak:\t  9000 kB\nVmHWM:\t  1676 kB\n").unwrap();
+
+        let process = ProcessStats::new(&root);
+        let io = process.io().unwrap();
+        assert_eq!(io, ProcessIo { rchar: 5000, wchar: 300, read_bytes: 4096, write_bytes: 0 });
+        assert_eq!(io.since(&ProcessIo { rchar: 1000, ..io }).rchar, 4000);
+        assert_eq!(process.peak_rss(), Some(1676 * 1024));
+        assert_eq!(ProcessStats::new(root.join("missing")).io(), None);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,99 +1,128 @@
-use std::{
-    sync::{mpsc, Arc, Mutex},
-    thread,
-};
-use std::sync::atomic::{AtomicBool, Ordering};
-
-pub trait Channel: Send + Sync + 'static {}
-impl<T: Send + Sync + 'static> Channel for T {}
-
-pub struct ThreadPool<T: Channel> {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<JobData<T>>>,
-}
-
-type Job<T> = Box<dyn FnOnce() -> T + Send + Sync + 'static>;
-
-struct JobData<T: Channel> {
-    job: Job<T>,
-    callback: mpsc::Sender<T>,
-}
-
-impl<T: Channel> ThreadPool<T> {
-    pub fn new(size: usize) -> ThreadPool<T> {
-        assert!(size > 0);
-
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);
-
-        for _ in 0..size {
-            workers.push(Worker::new(Arc::clone(&receiver)));
-        }
-
-        ThreadPool { workers, sender: Some(sender) }
-    }
-
-    pub fn size(&self) -> usize {
-        self.workers.len()
-    }
-
-    pub fn execute<F>(&self, f: F) -> mpsc::Receiver<T>
-        where
-            F: FnOnce() -> T + Channel,
-    {
-        let (callback_sender, callback_receiver) = mpsc::channel();
-        let job_data = JobData {
-            job: Box::new(f),
-            callback: callback_sender,
-        };
-        self.sender.as_ref().unwrap().send(job_data).unwrap();
-
-        callback_receiver
-    }
-
-    pub fn available_workers(&self) -> usize {
-        self.workers.iter().filter(|&worker| worker.is_available.load(Ordering::Relaxed)).count()
-    }
-
-    pub fn is_available(&self) -> bool {
-        self.available_workers() > 0
-    }
-}
-
-impl<T: Channel> Drop for ThreadPool<T> {
-    fn drop(&mut self) {
-        drop(self.sender.take());
-        for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
-        }
-    }
-}
-
-struct Worker {
-    is_available: Arc<AtomicBool>,
-    thread: Option<thread::JoinHandle<()>>,
-}
-
-impl Worker {
-    fn new<T: Channel>(receiver: Arc<Mutex<mpsc::Receiver<JobData<T>>>>) -> Worker {
-        let is_available = Arc::new(AtomicBool::new(true));
-        let is_available_copy = Arc::clone(&is_available);
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-            is_available_copy.store(false, Ordering::Relaxed);
-
-            match message {
-                Ok(job_data) => {
-                    let job_result = (job_data.job)();
-                    job_data.callback.send(job_result).unwrap();
-                }
-                Err(_) => break
-            };
-            is_available_copy.store(true, Ordering::Relaxed);
-        });
-        Worker { is_available, thread: Some(thread) }
-    }
-}
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crate::cancel::CancelToken;
+
+pub trait Channel: Send + Sync + 'static {}
+impl<T: Send + Sync + 'static> Channel for T {}
+
+pub struct ThreadPool<T: Channel> {
+    workers: Vec<Worker>,
+    sender: Option<mpsc::Sender<JobData<T>>>,
+    /// Jobs sent to the workers that haven't finished yet, including the ones still waiting for a worker
+    busy: Arc<AtomicUsize>,
+    /// Nanoseconds the workers spent running jobs
+    busy_time: Arc<AtomicU64>,
+}
+
+type Job<T> = Box<dyn FnOnce() -> T + Send + Sync + 'static>;
+/// Result given back for a job instead of running it, `None` when the job runs
+type Skip<T> = Arc<dyn Fn() -> Option<T> + Send + Sync + 'static>;
+
+struct JobData<T: Channel> {
+    job: Job<T>,
+    callback: mpsc::Sender<T>,
+}
+
+impl<T: Channel> ThreadPool<T> {
+    pub fn new(size: usize) -> ThreadPool<T> {
+        ThreadPool::with_skip(size, Arc::new(|| None))
+    }
+
+    fn with_skip(size: usize, skip: Skip<T>) -> ThreadPool<T> {
+        assert!(size > 0);
+
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = Vec::with_capacity(size);
+
+        let busy = Arc::new(AtomicUsize::new(0));
+        let busy_time = Arc::new(AtomicU64::new(0));
+        for _ in 0..size {
+            workers.push(Worker::new(Arc::clone(&receiver), Arc::clone(&busy), Arc::clone(&busy_time), Arc::clone(&skip)));
+        }
+
+        ThreadPool { workers, sender: Some(sender), busy, busy_time }
+    }
+
+    pub fn execute<F>(&self, f: F) -> mpsc::Receiver<T>
+        where
+            F: FnOnce() -> T + Channel,
+    {
+        let (callback_sender, callback_receiver) = mpsc::channel();
+        let job_data = JobData {
+            job: Box::new(f),
+            callback: callback_sender,
+        };
+        self.busy.fetch_add(1, Ordering::SeqCst);
+        self.sender.as_ref().unwrap().send(job_data).unwrap();
+
+        callback_receiver
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Workers that a job sent now would find idle. Checking it and sending jobs while the pool is locked
+    /// guarantees that they start right away instead of waiting behind other jobs.
+    pub fn available_workers(&self) -> usize {
+        self.size().saturating_sub(self.busy.load(Ordering::SeqCst))
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.available_workers() > 0
+    }
+
+    /// Time all the workers spent running the jobs that finished, which includes their waits
+    pub fn busy_time(&self) -> Duration {
+        Duration::from_nanos(self.busy_time.load(Ordering::SeqCst))
+    }
+}
+
+impl<T: Channel + Default> ThreadPool<T> {
+    /// Pool whose workers don't start the jobs left once `cancel` is cancelled, giving back `T::default()` instead.
+    /// A job is only skipped once the token is cancelled, so checking it tells a skipped job from one that ran.
+    pub fn cancellable(size: usize, cancel: CancelToken) -> ThreadPool<T> {
+        ThreadPool::with_skip(size, Arc::new(move || cancel.is_cancelled().then(T::default)))
+    }
+}
+
+impl<T: Channel> Drop for ThreadPool<T> {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+struct Worker {
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new<T: Channel>(receiver: Arc<Mutex<mpsc::Receiver<JobData<T>>>>, busy: Arc<AtomicUsize>, busy_time: Arc<AtomicU64>, skip: Skip<T>) -> Worker {
+        let thread = thread::spawn(move || loop {
+            let message = receiver.lock().unwrap().recv();
+
+            match message {
+                Ok(job_data) => {
+                    let start = Instant::now();
+                    let job_result = skip().unwrap_or_else(job_data.job);
+                    busy_time.fetch_add(start.elapsed().as_nanos() as u64, Ordering::SeqCst);
+                    // the worker is counted as idle before the result wakes up the thread waiting for it
+                    busy.fetch_sub(1, Ordering::SeqCst);
+                    job_data.callback.send(job_result).unwrap();
+                }
+                Err(_) => break
+            };
+        });
+        Worker { thread: Some(thread) }
+    }
+}